@@ -0,0 +1,56 @@
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+
+/// Credentials required to scrape `/metrics`, see `--metrics-auth`
+#[derive(Clone, Debug)]
+pub(crate) struct BasicAuth {
+    /// `user:password` as sent (base64 encoded) in the `Authorization` header
+    credentials: String,
+}
+
+impl BasicAuth {
+    /// Whether the value of an `Authorization` header carries the expected credentials
+    pub fn authorized(&self, header: Option<&str>) -> bool {
+        let decoded = header
+            .and_then(|header| header.split_once(' '))
+            .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+            .and_then(|(_, encoded)| STANDARD.decode(encoded.trim()).ok());
+
+        decoded.is_some_and(|decoded| constant_time_eq(&decoded, self.credentials.as_bytes()))
+    }
+}
+
+/// Compare two byte strings in time only depending on their length
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Validate `--metrics-auth`, it has to be given as `user:password`
+pub(crate) fn parse_basic_auth(credentials: &str) -> Result<BasicAuth, String> {
+    match credentials.split_once(':') {
+        Some((user, _)) if !user.is_empty() => Ok(BasicAuth {
+            credentials: credentials.to_owned(),
+        }),
+        _ => Err("must be given as `user:password`".into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checks_basic_credentials() {
+        let auth = parse_basic_auth("prometheus:s3cret").unwrap();
+        let header = format!("Basic {}", STANDARD.encode("prometheus:s3cret"));
+
+        assert!(auth.authorized(Some(&header)));
+        assert!(auth.authorized(Some(&header.replace("Basic", "basic"))));
+        assert!(!auth.authorized(None));
+        assert!(!auth.authorized(Some("Basic cHJvbWV0aGV1czp3cm9uZw==")));
+        assert!(!auth.authorized(Some("Bearer s3cret")));
+        assert!(!auth.authorized(Some("Basic not-base64")));
+        assert!(parse_basic_auth(":s3cret").is_err());
+        assert!(parse_basic_auth("prometheus").is_err());
+    }
+}
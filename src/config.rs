@@ -0,0 +1,222 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// Settings that can be read from a `--config` file.
+///
+/// The keys are the names of the corresponding command line options with `_`
+/// instead of `-`, `--label` is a `[labels]` table. Options given on the command
+/// line replace the ones from the file, including all values of repeatable options.
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    verbose: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    log_format: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moonraker_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moonraker_api_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ca_cert: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    insecure_skip_verify: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reconnect_interval: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reconnect_max_attempts: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    export_interval_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    statsd_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    influx_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    influx_token: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    influx_org: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    influx_bucket: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    prometheus_listen_address: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metrics_auth: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    openmetrics: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spoolman: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    announcements: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gcode_macro_variables: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance_name: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    labels: Option<BTreeMap<String, String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    include: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    exclude: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    drop_metric_prefix: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metric_prefix: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subscribe_retries: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_timeout_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    export_null_as_nan: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    heater_deadband: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stale_object_timeout_secs: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mcu_rtt_histogram: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    endstop_poll_interval_secs: Option<u64>,
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+
+        Self::parse(&content).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+
+    fn parse(content: &str) -> anyhow::Result<Self> {
+        Ok(toml::from_str(content)?)
+    }
+
+    /// The settings as command line arguments, so clap validates them like any other option.
+    ///
+    /// Settings `overridden` on the command line are left out.
+    pub fn into_args(self, overridden: impl Fn(&str) -> bool) -> anyhow::Result<Vec<String>> {
+        let Value::Object(settings) = serde_json::to_value(self)? else {
+            unreachable!("Config serializes to a map");
+        };
+
+        let mut args = Vec::new();
+        for (key, value) in settings.into_iter().filter(|(key, _)| !overridden(key)) {
+            let flag = format!("--{}", key.replace('_', "-"));
+            match (key.as_str(), value) {
+                ("verbose", Value::Number(count)) => {
+                    let count = count.as_u64().unwrap_or_default() as usize;
+                    args.extend(std::iter::repeat_n(flag, count));
+                }
+                ("labels", Value::Object(labels)) => {
+                    for (name, value) in labels {
+                        args.push(format!(
+                            "--label={name}={}",
+                            value.as_str().unwrap_or_default()
+                        ));
+                    }
+                }
+                (_, Value::Bool(enabled)) => args.extend(enabled.then_some(flag)),
+                (_, Value::Array(values)) => {
+                    args.extend(values.iter().map(|value| format!("{flag}={}", arg(value))))
+                }
+                (_, value) => args.push(format!("{flag}={}", arg(&value))),
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+fn arg(value: &Value) -> String {
+    match value {
+        Value::String(value) => value.to_owned(),
+        value => value.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_toml() {
+        let config = Config::parse(
+            r#"
+            # Printer in the garage
+            moonraker_url = "ws://voron.local:7125/websocket"
+            subscribe_retries = 5 # inline comment
+            include = [
+                "temperature_sensor *",
+                'extruder',
+            ]
+
+            [labels]
+            printer = "voron2\"4\""
+            "site" = 'garage'
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.into_args(|_| false).unwrap(),
+            vec![
+                "--include=temperature_sensor *",
+                "--include=extruder",
+                "--label=printer=voron2\"4\"",
+                "--label=site=garage",
+                "--moonraker-url=ws://voron.local:7125/websocket",
+                "--subscribe-retries=5",
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_line_of_syntax_errors() {
+        let error = Config::parse("spoolman = true\nsubscribe_retries = = 3\n").unwrap_err();
+        let message = format!("{error:#}");
+        assert_eq!(
+            message.lines().next(),
+            Some("TOML parse error at line 2, column 21"),
+            "{message}"
+        );
+        assert!(message.contains("2 | subscribe_retries = = 3"), "{message}");
+    }
+
+    #[test]
+    fn config_becomes_command_line_arguments() {
+        let config = Config::parse(
+            r#"
+            verbose = 2
+            moonraker_url = "ws://voron.local:7125/websocket"
+            spoolman = true
+            announcements = false
+            heater_deadband = 1.5
+            exclude = ["tmc*", "mcu rpi"]
+            labels = { printer = "voron24" }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.into_args(|_| false).unwrap(),
+            vec![
+                "--exclude=tmc*",
+                "--exclude=mcu rpi",
+                "--heater-deadband=1.5",
+                "--label=printer=voron24",
+                "--moonraker-url=ws://voron.local:7125/websocket",
+                "--spoolman",
+                "--verbose",
+                "--verbose",
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_unknown_settings() {
+        assert!(Config::parse("moonraker = \"ws://localhost\"").is_err());
+    }
+}
@@ -0,0 +1,59 @@
+//! Gzip compression of the `/metrics` response.
+
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use std::io::Write;
+
+/// Compress `data` into the gzip format of RFC 1952
+pub(crate) fn compress(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+    encoder
+        .write_all(data)
+        .and_then(|()| encoder.finish())
+        .expect("writing to a Vec never fails")
+}
+
+/// Whether an `Accept-Encoding` header value allows a gzip response
+pub(crate) fn accepted(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut parts = coding.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let disabled = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f64>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        (name.eq_ignore_ascii_case("gzip") || name == "*") && !disabled
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn compressed_text_decodes_to_the_original() {
+        let text = "klipper_stats_mcu_freq{name=\"mcu\"} 64000000\n".repeat(100);
+        let compressed = compress(text.as_bytes());
+        assert!(compressed.len() < text.len() / 10, "{}", compressed.len());
+
+        let mut decoded = String::new();
+        GzDecoder::new(compressed.as_slice())
+            .read_to_string(&mut decoded)
+            .unwrap();
+        assert_eq!(decoded, text);
+    }
+
+    #[test]
+    fn accept_encoding_is_negotiated() {
+        assert!(accepted("gzip"));
+        assert!(accepted("deflate, GZIP;q=0.5"));
+        assert!(accepted("*"));
+        assert!(!accepted("gzip;q=0"));
+        assert!(!accepted("br, deflate"));
+        assert!(!accepted(""));
+    }
+}
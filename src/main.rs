@@ -1,43 +1,278 @@
-use crate::moonraker::UpdateHandlerError;
-use anyhow::Result;
+use crate::auth::{parse_basic_auth, BasicAuth};
+use crate::config::Config;
+use crate::moonraker::{
+    websocket_url, ConnectOptions, ObjectFilter, ReconnectPolicy, UpdateHandlerError,
+    UpdateHandlerOptions,
+};
+use crate::output::{InfluxOptions, InfluxRecorder, Output, Push, StatsdRecorder};
+use crate::recorder::{
+    parse_label, parse_metric_prefix, GlobalLabels, GlobalLabelsLayer, MetricNamesLayer,
+    ReplaceableRecorder,
+};
+use anyhow::{Context, Result};
 use bytes::Bytes;
-use clap::{ArgAction, ColorChoice, Parser};
+use clap::error::ErrorKind;
+use clap::parser::ValueSource;
+use clap::{ArgAction, ColorChoice, CommandFactory, Parser};
 use http_body_util::Full;
 use hyper::body::Incoming as IncomingBody;
+use hyper::header::{
+    ACCEPT, ACCEPT_ENCODING, AUTHORIZATION, CONTENT_ENCODING, CONTENT_TYPE, VARY, WWW_AUTHENTICATE,
+};
 use hyper::server::conn::http1;
 use hyper::service::Service;
-use hyper::{Request, Response};
+use hyper::{Request, Response, StatusCode};
 use hyper_util::rt::TokioIo;
-use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use metrics::Recorder;
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle, PrometheusRecorder};
+use metrics_util::layers::Stack;
+use metrics_util::MetricKindMask;
 use moonraker::UpdateHandler;
+use std::ffi::OsString;
 use std::future::Future;
 use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::pin::Pin;
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::TcpListener;
 use tokio::task::JoinSet;
-use tracing::{error, Level};
+use tracing::{error, info, Level};
+use url::Url;
 
+mod auth;
+mod config;
+mod gzip;
 mod moonraker;
+mod openmetrics;
+mod output;
+mod recorder;
 mod types;
 
 /// Prometheus exporter for Moonraker.
 #[derive(clap::Parser, Debug)]
-#[clap(author, about, version, name = "mamalluca", color=ColorChoice::Auto)]
+#[clap(
+    author,
+    about,
+    version,
+    name = "mamalluca",
+    color=ColorChoice::Auto,
+    args_override_self = true
+)]
 pub(crate) struct Cli {
+    /// TOML file to read settings from, options given on the command line take precedence
+    #[clap(long)]
+    config: Option<PathBuf>,
     /// Verbose mode (-v, -vv, -vvv, etc.)
     #[clap(short, long, action=ArgAction::Count)]
     verbose: u8,
+    /// Format of the log output
+    #[clap(long, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
     /// Moonraker URL
     #[clap(short, long, default_value = "ws://127.0.0.1:7125/websocket")]
     moonraker_url: url::Url,
+    /// Moonraker API key, required if Moonraker's `[authorization]` component rejects
+    /// unauthenticated clients
+    #[clap(long, value_parser = parse_api_key)]
+    moonraker_api_key: Option<String>,
+    /// PEM encoded CA certificate to trust for `wss://` Moonraker URLs, e.g. for self-signed
+    /// certificates
+    #[clap(long)]
+    ca_cert: Option<PathBuf>,
+    /// Don't verify the certificate of `wss://` Moonraker URLs. Only use this for testing!
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value_t = false
+    )]
+    insecure_skip_verify: bool,
+    /// Seconds to wait before reconnecting to Moonraker, doubled after every failed attempt
+    #[clap(long, default_value_t = 5, value_parser = clap::value_parser!(u64).range(1..))]
+    reconnect_interval: u64,
+    /// Exit after this many consecutive failed attempts to connect to Moonraker.
+    ///
+    /// Retries forever by default.
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    reconnect_max_attempts: Option<u32>,
+    /// Seconds between two exports of the current printer state with `--output statsd`/`influx`.
+    ///
+    /// Metrics served to Prometheus are exported whenever `/metrics` is scraped.
+    #[clap(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    export_interval_secs: u64,
+    /// Where to export metrics to
+    #[clap(long, value_enum, default_value_t = Output::Prometheus)]
+    output: Output,
+    /// StatsD server to push metrics to with `--output statsd`
+    #[clap(long, value_name = "HOST:PORT")]
+    statsd_address: Option<String>,
+    /// Base URL of the InfluxDB v2 server to write to with `--output influx`
+    #[clap(long, value_name = "URL")]
+    influx_url: Option<Url>,
+    /// API token with write access to `--influx-bucket`
+    #[clap(long)]
+    influx_token: Option<String>,
+    /// Organization owning `--influx-bucket`
+    #[clap(long)]
+    influx_org: Option<String>,
+    /// Bucket metrics are written to with `--output influx`
+    #[clap(long)]
+    influx_bucket: Option<String>,
     /// Prometheus Listener Socket
     #[clap(short, long, default_value = "0.0.0.0:9000")]
     prometheus_listen_address: SocketAddr,
+    /// Require HTTP basic authentication with these credentials to scrape `/metrics`
+    #[clap(long, value_name = "USER:PASSWORD", value_parser = parse_basic_auth)]
+    metrics_auth: Option<BasicAuth>,
+    /// Answer scrapers asking for it in the OpenMetrics text format, which appends `_total`
+    /// to the name of every counter that lacks it
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value_t = false
+    )]
+    openmetrics: bool,
+    /// Export the active Spoolman spool (requires Moonraker's `[spoolman]` component)
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value_t = false
+    )]
+    spoolman: bool,
+    /// Export the number of unread Moonraker announcements
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value_t = false
+    )]
+    announcements: bool,
+    /// Export the numeric variables of all `gcode_macro` objects
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value_t = false
+    )]
+    gcode_macro_variables: bool,
+    /// Value of the `instance` label added to all metrics.
+    ///
+    /// Defaults to the hostname reported by Moonraker, or the host of the Moonraker URL
+    /// if that is not available.
+    #[clap(long)]
+    instance_name: Option<String>,
+    /// Additional `key=value` label added to all metrics, can be repeated
+    #[clap(long = "label", value_name = "KEY=VALUE", value_parser = parse_label)]
+    labels: Vec<(String, String)>,
+    /// Only subscribe to Klipper objects matching this glob, e.g. `temperature_sensor *`.
+    ///
+    /// Can be repeated, all supported objects are subscribed to if not given.
+    #[clap(long, value_name = "GLOB")]
+    include: Vec<String>,
+    /// Don't subscribe to Klipper objects matching this glob, can be repeated
+    #[clap(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+    /// Drop the leading `klipper.`/`moonraker.` segment from all metric names.
+    ///
+    /// This renames every exported series, e.g. `klipper_stats_mcu_freq` becomes `stats_mcu_freq`.
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value_t = false
+    )]
+    drop_metric_prefix: bool,
+    /// Prefix prepended to all metric names, e.g. `printer_a` exports `printer_a_klipper_stats_mcu_freq`.
+    ///
+    /// Applied after `--drop-metric-prefix`, so both can be combined to replace the prefix.
+    #[clap(long, value_parser = parse_metric_prefix)]
+    metric_prefix: Option<String>,
+    /// Retries when listing or subscribing to Klipper objects fails while Klippy is unavailable
+    #[clap(long, default_value_t = 3)]
+    subscribe_retries: u32,
+    /// Seconds to wait for Moonraker to answer a request before retrying it
+    #[clap(long, default_value_t = 10, value_parser = clap::value_parser!(u64).range(1..))]
+    request_timeout_secs: u64,
+    /// Export optional values that Klipper does not report (e.g. extruder `time_offset`) as NaN
+    /// instead of omitting the metric
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value_t = false
+    )]
+    export_null_as_nan: bool,
+    /// Maximum deviation in °C from the target temperature still considered "at target"
+    #[clap(long, default_value_t = 2.0)]
+    heater_deadband: f64,
+    /// Drop gauges that have not been exported for this many seconds, e.g. of sensors
+    /// removed from the config
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    stale_object_timeout_secs: Option<u64>,
+    /// Also record the MCU round trip times (`srtt`, `rttvar`, `rto`) as histograms to catch
+    /// spikes between scrapes, at the cost of more series
+    #[clap(
+        long,
+        value_name = "BOOL",
+        action = ArgAction::Set,
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "true",
+        default_value_t = false
+    )]
+    mcu_rtt_histogram: bool,
+    /// Query the endstops every this many seconds while not printing, e.g. to diagnose a
+    /// flaky endstop
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..))]
+    endstop_poll_interval_secs: Option<u64>,
+    /// Connect to Moonraker, print the objects Klipper reports and exit
+    #[clap(long)]
+    dry_run: bool,
 }
 
-fn setup_logging(verbose: u8) -> Result<()> {
+/// Validate a `--moonraker-api-key`, it is sent as HTTP header value
+fn parse_api_key(key: &str) -> Result<String, String> {
+    if !key.is_empty() && key.chars().all(|c| c.is_ascii_graphic()) {
+        Ok(key.to_owned())
+    } else {
+        Err("must only contain printable ASCII characters".into())
+    }
+}
+
+/// Formats selectable with `--log-format`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line, including the fields of the current spans
+    Json,
+}
+
+fn setup_logging(verbose: u8, format: LogFormat) -> Result<()> {
     let log_level = match verbose {
         0 => Level::WARN,
         1 => Level::INFO,
@@ -46,26 +281,118 @@ fn setup_logging(verbose: u8) -> Result<()> {
     };
 
     // Logging
-    tracing_subscriber::fmt().with_max_level(log_level).init();
+    let logging = tracing_subscriber::fmt().with_max_level(log_level);
+    match format {
+        LogFormat::Text => logging.init(),
+        LogFormat::Json => logging.json().init(),
+    }
 
     Ok(())
 }
 
-fn setup_exporter() -> Result<HttpExporterService> {
-    let builder = PrometheusBuilder::new();
-    let handle = builder.install_recorder()?;
+/// How the metrics of the installed recorder leave the exporter
+enum Exporter {
+    /// Scraped from the HTTP service
+    Serve(HttpExporterService),
+    /// Sent by the recorder whenever a metric is updated
+    Stream,
+    /// Sent after every export cycle
+    Push(Box<dyn Push>),
+}
+
+/// Install the recorder for `--output`
+fn setup_exporter(
+    args: &Cli,
+    labels: GlobalLabels,
+    handler: &Arc<UpdateHandler>,
+) -> Result<Exporter> {
+    match args.output {
+        Output::Prometheus => {
+            let stale_timeout = args.stale_object_timeout_secs.map(Duration::from_secs);
+            // Forget the series of objects that are no longer exported
+            let build = move || {
+                PrometheusBuilder::new()
+                    .idle_timeout(MetricKindMask::GAUGE, stale_timeout)
+                    .build_recorder()
+            };
+            let recorder = ReplaceableRecorder::new(build());
+            // Start over once the instance label changes, instead of exporting the
+            // series of the previous instance forever
+            labels.on_change({
+                let recorder = recorder.clone();
+                move || recorder.replace(build())
+            });
+            install_recorder(args, labels, recorder.clone())?;
+            Ok(Exporter::Serve(HttpExporterService::new(
+                recorder,
+                handler.clone(),
+                args.metrics_auth.clone(),
+                args.openmetrics,
+            )))
+        }
+        Output::Statsd => {
+            let address = args.statsd_address.as_deref().unwrap_or_default();
+            install_recorder(args, labels, StatsdRecorder::new(address)?)?;
+            Ok(Exporter::Stream)
+        }
+        Output::Influx => {
+            let recorder = InfluxRecorder::new();
+            let writer = recorder.writer(InfluxOptions {
+                url: args
+                    .influx_url
+                    .clone()
+                    .context("--influx-url is required")?,
+                token: args.influx_token.clone().unwrap_or_default(),
+                org: args.influx_org.clone().unwrap_or_default(),
+                bucket: args.influx_bucket.clone().unwrap_or_default(),
+            })?;
+            labels.on_change({
+                let recorder = recorder.clone();
+                move || recorder.clear()
+            });
+            install_recorder(args, labels, recorder)?;
+            Ok(Exporter::Push(Box::new(writer)))
+        }
+    }
+}
 
-    Ok(HttpExporterService::new(handle))
+fn install_recorder<R>(args: &Cli, labels: GlobalLabels, recorder: R) -> Result<()>
+where
+    R: Recorder + Sync + 'static,
+{
+    Stack::new(recorder)
+        .push(GlobalLabelsLayer::new(labels))
+        .push(MetricNamesLayer::new(
+            args.drop_metric_prefix,
+            args.metric_prefix.clone(),
+        ))
+        .install()
+        .map_err(|_| anyhow::anyhow!("Failed to install metrics recorder"))
 }
 
 #[derive(Clone)]
 struct HttpExporterService {
-    handle: PrometheusHandle,
+    recorder: ReplaceableRecorder<PrometheusRecorder>,
+    /// Exports the current printer state right before it is rendered for a scrape
+    handler: Arc<UpdateHandler>,
+    auth: Option<BasicAuth>,
+    /// Whether the OpenMetrics text format may be negotiated, see `--openmetrics`
+    openmetrics: bool,
 }
 
 impl HttpExporterService {
-    pub fn new(handle: PrometheusHandle) -> Self {
-        Self { handle }
+    pub fn new(
+        recorder: ReplaceableRecorder<PrometheusRecorder>,
+        handler: Arc<UpdateHandler>,
+        auth: Option<BasicAuth>,
+        openmetrics: bool,
+    ) -> Self {
+        Self {
+            recorder,
+            handler,
+            auth,
+            openmetrics,
+        }
     }
 }
 
@@ -75,50 +402,171 @@ impl Service<Request<IncomingBody>> for HttpExporterService {
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn call(&self, req: Request<IncomingBody>) -> Self::Future {
-        fn mk_response(s: String) -> Result<Response<Full<Bytes>>, hyper::Error> {
-            Ok(Response::builder().body(Full::new(Bytes::from(s))).unwrap())
+        fn mk_response(
+            status: StatusCode,
+            s: String,
+        ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+            Ok(Response::builder()
+                .status(status)
+                .body(Full::new(Bytes::from(s)))
+                .unwrap())
         }
 
-        let handle = self.handle.clone();
+        fn mk_metrics_response(
+            s: String,
+            content_type: &'static str,
+            gzip: bool,
+        ) -> Result<Response<Full<Bytes>>, hyper::Error> {
+            let builder = Response::builder()
+                .header(CONTENT_TYPE, content_type)
+                .header(VARY, "Accept, Accept-Encoding");
+            let response = if gzip {
+                builder
+                    .header(CONTENT_ENCODING, "gzip")
+                    .body(Full::new(Bytes::from(gzip::compress(s.as_bytes()))))
+            } else {
+                builder.body(Full::new(Bytes::from(s)))
+            };
+            Ok(response.unwrap())
+        }
 
-        let res = match req.uri().path() {
-            "/health" => mk_response("OK".into()),
-            _ => mk_response(handle.render()),
-        };
+        fn render_metrics(handle: &PrometheusHandle, openmetrics: bool) -> (String, &'static str) {
+            if openmetrics {
+                (
+                    openmetrics::render(&handle.render()),
+                    openmetrics::CONTENT_TYPE,
+                )
+            } else {
+                (handle.render(), "text/plain; version=0.0.4; charset=utf-8")
+            }
+        }
 
-        Box::pin(async { res })
+        fn mk_unauthorized_response() -> Result<Response<Full<Bytes>>, hyper::Error> {
+            Ok(Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .header(WWW_AUTHENTICATE, "Basic realm=\"mamalluca\"")
+                .body(Full::new(Bytes::from("Unauthorized")))
+                .unwrap())
+        }
+
+        let recorder = self.recorder.clone();
+        let handler = self.handler.clone();
+        let authorized = self.auth.as_ref().is_none_or(|auth| {
+            auth.authorized(
+                req.headers()
+                    .get(AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok()),
+            )
+        });
+        let gzip = req
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(gzip::accepted);
+        let openmetrics = self.openmetrics
+            && req
+                .headers()
+                .get(ACCEPT)
+                .and_then(|value| value.to_str().ok())
+                .is_some_and(openmetrics::accepted);
+
+        let path = req.uri().path().to_owned();
+
+        Box::pin(async move {
+            match path.as_str() {
+                "/metrics" if !authorized => mk_unauthorized_response(),
+                "/metrics" => {
+                    if let Err(err) = handler.export().await {
+                        error!("Failed to export metrics: {}", err);
+                    }
+                    let handle = recorder.current().handle();
+                    let (body, content_type) = render_metrics(&handle, openmetrics);
+                    mk_metrics_response(body, content_type, gzip)
+                }
+                "/health" => mk_response(StatusCode::OK, "OK".into()),
+                _ => mk_response(StatusCode::NOT_FOUND, "Not Found".into()),
+            }
+        })
     }
 }
 
+/// How often to ask Moonraker's `update_manager` for pending software updates
+const UPDATE_STATUS_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
 async fn run(args: &Cli) -> Result<()> {
-    let (handler, future) = UpdateHandler::new(&args.moonraker_url).await?;
+    let labels = GlobalLabels::default();
+    for (key, value) in &args.labels {
+        labels.insert(key.to_owned(), value.to_owned());
+    }
+    let options = UpdateHandlerOptions {
+        spoolman: args.spoolman,
+        announcements: args.announcements,
+        gcode_macro_variables: args.gcode_macro_variables,
+        instance_name: args.instance_name.clone(),
+        subscribe_retries: args.subscribe_retries,
+        request_timeout: Duration::from_secs(args.request_timeout_secs),
+        null_as_nan: args.export_null_as_nan,
+        heater_deadband: args.heater_deadband,
+        mcu_rtt_histogram: args.mcu_rtt_histogram,
+        connection: ConnectOptions {
+            api_key: args.moonraker_api_key.clone(),
+            ca_cert: args.ca_cert.clone(),
+            insecure_skip_verify: args.insecure_skip_verify,
+            reconnect: ReconnectPolicy {
+                interval: Duration::from_secs(args.reconnect_interval),
+                max_attempts: args.reconnect_max_attempts,
+            },
+        },
+        objects: ObjectFilter {
+            include: args.include.clone(),
+            exclude: args.exclude.clone(),
+        },
+    };
+    let (handler, future) = UpdateHandler::new(
+        &websocket_url(&args.moonraker_url)?,
+        labels.clone(),
+        options,
+    )
+    .await?;
     let handler = Arc::new(handler);
 
-    let exporter = setup_exporter()?;
-    let listener = TcpListener::bind(&args.prometheus_listen_address).await?;
+    if args.dry_run {
+        return dry_run(&handler).await;
+    }
 
     let mut set = JoinSet::new();
 
-    // Start the HTTP server
-    set.spawn({
-        async move {
-            loop {
-                let (stream, _) = listener.accept().await?;
-                let io = TokioIo::new(stream);
-                let service = exporter.clone();
-
-                tokio::task::spawn(async move {
-                    if let Err(err) = http1::Builder::new()
-                        .keep_alive(false)
-                        .serve_connection(io, service)
-                        .await
-                    {
-                        error!("Failed to serve HTTP connection: {:?}", err)
-                    }
-                });
+    let exporter = setup_exporter(args, labels, &handler)?;
+
+    // Start the HTTP server, if metrics are pulled
+    if let Exporter::Serve(exporter) = &exporter {
+        let exporter = exporter.clone();
+        let listener = TcpListener::bind(&args.prometheus_listen_address).await?;
+        info!(
+            "Serving metrics on http://{}/metrics",
+            listener.local_addr()?
+        );
+
+        set.spawn({
+            async move {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    let io = TokioIo::new(stream);
+                    let service = exporter.clone();
+
+                    tokio::task::spawn(async move {
+                        if let Err(err) = http1::Builder::new()
+                            .keep_alive(false)
+                            .serve_connection(io, service)
+                            .await
+                        {
+                            error!("Failed to serve HTTP connection: {:?}", err)
+                        }
+                    });
+                }
             }
-        }
-    });
+        });
+    }
 
     // Start the update handler
     set.spawn({
@@ -126,35 +574,277 @@ async fn run(args: &Cli) -> Result<()> {
         async move { handler.process().await }
     });
 
-    // Start the periodic metrics update
+    // Start the periodic metrics update, pulled metrics are exported on every scrape instead
+    if !matches!(exporter, Exporter::Serve(_)) {
+        let handler = handler.clone();
+        let mut interval = tokio::time::interval(Duration::from_secs(args.export_interval_secs));
+        set.spawn(async move {
+            loop {
+                interval.tick().await;
+                handler.export().await?;
+                if let Exporter::Push(output) = &exporter {
+                    if let Err(err) = output.push().await {
+                        error!("Failed to push metrics: {:#}", err);
+                    }
+                }
+            }
+        });
+    }
+
+    // Endstops are not part of the subscription and have to be queried
+    if let Some(secs) = args.endstop_poll_interval_secs {
+        let handler = handler.clone();
+        let mut interval = tokio::time::interval(Duration::from_secs(secs));
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        set.spawn(async move {
+            loop {
+                interval.tick().await;
+                if let Err(err) = handler.refresh_endstops().await {
+                    error!("Failed to query the endstops: {:#}", err);
+                }
+            }
+        });
+    }
+
+    // Poll for pending software updates, Moonraker does not announce them
     set.spawn({
-        let mut interval = tokio::time::interval(Duration::from_secs(1));
+        let handler = handler.clone();
+        let mut interval = tokio::time::interval(UPDATE_STATUS_INTERVAL);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
         async move {
             loop {
                 interval.tick().await;
-                handler.export().await?;
+                if let Err(err) = handler.refresh_update_status().await {
+                    error!("Failed to refresh the update status: {:#}", err);
+                }
             }
         }
     });
 
+    // The connection is only closed for good once reconnecting is given up
     set.spawn(async move {
         future
             .await
-            .map_err(|_e| UpdateHandlerError::FatalMoonrakerConnectionError)
+            .map_err(|_e| UpdateHandlerError::FatalMoonrakerConnectionError)?;
+        Err(UpdateHandlerError::FatalMoonrakerConnectionError)
     });
 
-    // Wait for the first task to exit
-    if let Some(result) = set.join_next().await {
-        result??
+    // Wait for the first task to exit or a request to terminate
+    tokio::select! {
+        Some(result) = set.join_next() => result??,
+        result = shutdown_signal() => {
+            result?;
+            info!("Shutting down");
+            handler.close().await;
+            set.shutdown().await;
+        }
+    }
+
+    Ok(())
+}
+
+/// Print which of the objects reported by Klipper are exported, see `--dry-run`
+async fn dry_run(handler: &UpdateHandler) -> Result<()> {
+    let objects = handler
+        .dry_run()
+        .await
+        .context("Failed to list the Klipper objects")?;
+    handler.close().await;
+
+    let sections = [
+        ("Subscribed objects", objects.subscribed),
+        (
+            "Excluded by --include, --exclude or --gcode-macro-variables",
+            objects.excluded,
+        ),
+        ("Unsupported objects", objects.unsupported),
+    ];
+    for (title, names) in sections {
+        println!("{title} ({}):", names.len());
+        for name in names {
+            println!("  {name}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolves once the process receives SIGINT or SIGTERM (Ctrl-C on Windows)
+async fn shutdown_signal() -> Result<()> {
+    #[cfg(unix)]
+    {
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result?,
+            _ = terminate.recv() => {}
+        }
     }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
 
     Ok(())
 }
 
+impl Cli {
+    /// Parse the command line merged with the settings from `--config`
+    fn from_args(mut argv: Vec<OsString>) -> Result<Self> {
+        let matches = Self::command().try_get_matches_from(&argv)?;
+        if let Some(path) = matches.get_one::<PathBuf>("config") {
+            // Settings given on the command line replace the ones from the file
+            let settings = Config::load(path)?
+                .into_args(|key| matches.value_source(key) == Some(ValueSource::CommandLine))?;
+            argv.splice(1..1, settings.into_iter().map(OsString::from));
+        }
+
+        let args = Self::try_parse_from(argv)?;
+        args.validate()?;
+        Ok(args)
+    }
+
+    /// Check the options required by the selected `--output`
+    fn validate(&self) -> Result<(), clap::Error> {
+        let (output, required) = match self.output {
+            Output::Prometheus => return Ok(()),
+            Output::Statsd => (
+                "statsd",
+                vec![("--statsd-address", self.statsd_address.is_some())],
+            ),
+            Output::Influx => (
+                "influx",
+                vec![
+                    ("--influx-url", self.influx_url.is_some()),
+                    ("--influx-token", self.influx_token.is_some()),
+                    ("--influx-org", self.influx_org.is_some()),
+                    ("--influx-bucket", self.influx_bucket.is_some()),
+                ],
+            ),
+        };
+        let missing = required
+            .into_iter()
+            .filter_map(|(flag, present)| (!present).then_some(flag))
+            .collect::<Vec<_>>();
+        if missing.is_empty() {
+            return Ok(());
+        }
+
+        Err(Self::command().error(
+            ErrorKind::MissingRequiredArgument,
+            format!("{} required with --output {output}", missing.join(", ")),
+        ))
+    }
+}
+
 #[tokio::main]
 async fn main() -> Result<()> {
-    let args = Cli::parse();
-    setup_logging(args.verbose)?;
+    let args = match Cli::from_args(std::env::args_os().collect()) {
+        Ok(args) => args,
+        Err(err) => match err.downcast::<clap::Error>() {
+            // Print usage errors, `--help` and `--version` like clap does
+            Ok(err) => err.exit(),
+            Err(err) => return Err(err),
+        },
+    };
+    setup_logging(args.verbose, args.log_format)?;
 
     run(&args).await
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(argv: &[&str]) -> Result<Cli> {
+        Cli::from_args(
+            std::iter::once("mamalluca")
+                .chain(argv.iter().copied())
+                .map(OsString::from)
+                .collect(),
+        )
+    }
+
+    /// Parse `argv` with `--config` pointing to a file with `settings`
+    fn args_with_config(name: &str, settings: &str, argv: &[&str]) -> Result<Cli> {
+        let path =
+            std::env::temp_dir().join(format!("mamalluca-{}-{name}.toml", std::process::id()));
+        std::fs::write(&path, settings).unwrap();
+
+        let config = ["--config", path.to_str().unwrap()];
+        let parsed = args(&[&config[..], argv].concat());
+        std::fs::remove_file(&path).unwrap();
+        parsed
+    }
+
+    #[test]
+    fn config_supplies_options_required_by_the_output() {
+        let parsed = args_with_config(
+            "statsd",
+            "statsd_address = \"127.0.0.1:8125\"\nspoolman = true\n",
+            &["--output", "statsd"],
+        )
+        .unwrap();
+
+        assert_eq!(parsed.output, Output::Statsd);
+        assert_eq!(parsed.statsd_address.as_deref(), Some("127.0.0.1:8125"));
+        assert!(parsed.spoolman);
+    }
+
+    #[test]
+    fn command_line_replaces_config_settings() {
+        let settings = r#"
+            verbose = 2
+            spoolman = true
+            drop_metric_prefix = true
+            include = ["extruder", "heater_bed"]
+            labels = { site = "garage" }
+            "#;
+
+        let parsed = args_with_config(
+            "override",
+            settings,
+            &[
+                "-v",
+                "--spoolman=false",
+                "--include",
+                "temperature_sensor *",
+                "--label",
+                "rack=2",
+            ],
+        )
+        .unwrap();
+        assert_eq!(parsed.verbose, 1);
+        assert!(!parsed.spoolman);
+        assert!(parsed.drop_metric_prefix);
+        assert_eq!(parsed.include, vec!["temperature_sensor *"]);
+        assert_eq!(parsed.labels, vec![("rack".to_owned(), "2".to_owned())]);
+
+        let parsed = args_with_config("defaults", settings, &[]).unwrap();
+        assert_eq!(parsed.verbose, 2);
+        assert!(parsed.spoolman);
+        assert_eq!(parsed.include, vec!["extruder", "heater_bed"]);
+        assert_eq!(
+            parsed.labels,
+            vec![("site".to_owned(), "garage".to_owned())]
+        );
+    }
+
+    #[test]
+    fn requires_the_options_of_the_output() {
+        let error = args(&[
+            "--output",
+            "influx",
+            "--influx-url",
+            "http://localhost:8086",
+        ])
+        .unwrap_err()
+        .downcast::<clap::Error>()
+        .unwrap();
+        assert_eq!(error.kind(), ErrorKind::MissingRequiredArgument);
+        assert!(error.to_string().contains(
+            "--influx-token, --influx-org, --influx-bucket required with --output influx"
+        ));
+
+        assert!(args(&["--output", "statsd"]).is_err());
+        assert!(args(&[]).is_ok());
+    }
+}
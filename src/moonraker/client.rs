@@ -1,22 +1,43 @@
-use crate::moonraker::types::JsonRPCRequest;
+use crate::moonraker::connector::{ClientConnectorTls, ConnectOptions, ReconnectPolicy};
+use crate::moonraker::types::{JsonRPCRequest, JsonRPCResponse, RpcResult};
 use crate::moonraker::Payload;
 
 use async_trait::async_trait;
 use dashmap::DashMap;
 use ezsockets::client::ClientCloseMode;
-use ezsockets::{ClientConfig, CloseFrame, Error};
+use ezsockets::{ClientConfig, CloseFrame, Error, WSError};
+use metrics::{counter, gauge};
 use serde_json::json;
 use std::collections::HashMap;
 use std::future::Future;
-use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot::Sender;
 use url::Url;
 type ConnectionID = u64;
+
+/// Header used to authenticate with Moonraker's `[authorization]` component
+const API_KEY_HEADER: &str = "X-Api-Key";
+
 #[derive(Debug)]
 pub(crate) enum MoonrakerCommands {
-    GetObjectList(Sender<serde_json::Value>),
-    Subscribe((Sender<serde_json::Value>, Vec<String>)),
+    GetObjectList(Sender<RpcResult>),
+    GetPrinterInfo(Sender<RpcResult>),
+    Subscribe((Sender<RpcResult>, Vec<String>)),
+    GetActiveSpool(Sender<RpcResult>),
+    GetSpool((Sender<RpcResult>, u64)),
+    ListAnnouncements(Sender<RpcResult>),
+    GetServerInfo(Sender<RpcResult>),
+    GetSystemInfo(Sender<RpcResult>),
+    GetProcStats(Sender<RpcResult>),
+    GetUpdateStatus(Sender<RpcResult>),
+    ListPowerDevices(Sender<RpcResult>),
+    GetHistoryTotals(Sender<RpcResult>),
+    GetTemperatureStore(Sender<RpcResult>),
+    QueryEndstops(Sender<RpcResult>),
+    GetFileMetadata((Sender<RpcResult>, String)),
 }
 
 #[derive(Debug, strum::Display)]
@@ -26,27 +47,101 @@ pub(crate) enum MoonrakerStatusNotification {
     KlippyReady,
     KlippyShutdown,
     KlippyDisconnected,
+    ActiveSpoolSet(Payload),
+    AnnouncementsChanged,
+    PowerChanged,
+    HistoryChanged,
+    CpuThrottled(Payload),
+    FileListChanged(Payload),
+    GcodeResponse(String),
     KlipperStatusData(Payload),
     MoonrakerStatusData(Payload),
 }
 
+/// Health of the websocket connection, updated by the [`Client`] callbacks
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionStats {
+    connected: AtomicBool,
+    /// Unix time the current connection was established at, `0` while disconnected
+    connected_since: AtomicU64,
+    /// Connections lost since the exporter started
+    reconnects: AtomicU64,
+}
+
+impl ConnectionStats {
+    fn connected(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.connected_since.store(now.as_secs(), Ordering::Relaxed);
+        self.connected.store(true, Ordering::Relaxed);
+    }
+
+    fn disconnected(&self) {
+        // Both callbacks may fire for the same connection
+        if self.connected.swap(false, Ordering::Relaxed) {
+            self.connected_since.store(0, Ordering::Relaxed);
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected.load(Ordering::Relaxed)
+    }
+
+    pub fn export(&self) {
+        gauge!("mamalluca.moonraker_connected")
+            .set(self.connected.load(Ordering::Relaxed) as u8 as f64);
+        gauge!("mamalluca.moonraker_connected_since_seconds")
+            .set(self.connected_since.load(Ordering::Relaxed) as f64);
+        counter!("mamalluca.moonraker_reconnects_total")
+            .absolute(self.reconnects.load(Ordering::Relaxed));
+    }
+}
+
 #[derive(Debug)]
 struct MoonrakerClientState {
-    requests: DashMap<ConnectionID, Sender<serde_json::Value>>,
+    requests: DashMap<ConnectionID, Sender<RpcResult>>,
     next_id: AtomicU64,
 }
 
+impl MoonrakerClientState {
+    /// Remember where to send the result of request `id`
+    fn register(&self, id: ConnectionID, tx: Sender<RpcResult>) {
+        // Requests given up on by the caller, e.g. after a timeout, are never answered
+        self.requests.retain(|_, tx| !tx.is_closed());
+        self.requests.insert(id, tx);
+    }
+
+    /// Drop all pending requests, their callers receive an error instead of waiting forever
+    fn abandon_requests(&self) {
+        if !self.requests.is_empty() {
+            tracing::debug!(
+                pending = self.requests.len(),
+                "Connection lost, abandoning pending requests"
+            );
+        }
+        self.requests.clear();
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Client {
     handle: ezsockets::Client<Self>,
     updates: mpsc::Sender<MoonrakerStatusNotification>,
     state: MoonrakerClientState,
+    stats: Arc<ConnectionStats>,
+    reconnect: ReconnectPolicy,
+    /// Connection attempts that failed since the last successful connect
+    failed_attempts: u32,
 }
 
 impl Client {
     fn new(
         connection: ezsockets::Client<Self>,
         updates: mpsc::Sender<MoonrakerStatusNotification>,
+        stats: Arc<ConnectionStats>,
+        reconnect: ReconnectPolicy,
     ) -> Self {
         Self {
             handle: connection,
@@ -55,28 +150,84 @@ impl Client {
                 requests: DashMap::new(),
                 next_id: AtomicU64::new(0),
             },
+            stats,
+            reconnect,
+            failed_attempts: 0,
         }
     }
 
     pub async fn connect(
         url: &str,
+        options: &ConnectOptions,
         updates: mpsc::Sender<MoonrakerStatusNotification>,
+        stats: Arc<ConnectionStats>,
     ) -> anyhow::Result<(
         ezsockets::Client<Client>,
         impl Future<Output = Result<(), ezsockets::Error>>,
     )> {
         let url = Url::parse(url)?;
-        let config = ClientConfig::new(url);
-        Ok(ezsockets::connect(|handle| Client::new(handle, updates), config).await)
+        let mut config = ClientConfig::new(url).reconnect_interval(options.reconnect.interval);
+        // Moonraker authorizes the websocket upgrade request like any other HTTP request
+        if let Some(api_key) = &options.api_key {
+            config = config.header(API_KEY_HEADER, api_key.as_str());
+        }
+
+        let connector = ClientConnectorTls::new(options)?;
+        let reconnect = options.reconnect.clone();
+        let (handle, mut future) = ezsockets::connect_with(
+            |handle| Client::new(handle, updates, stats, reconnect),
+            config,
+            connector,
+        );
+        let future = async move {
+            future
+                .extract()
+                .await
+                .unwrap_or(Err("client actor crashed".into()))
+        };
+
+        Ok((handle, future))
+    }
+
+    /// Send a JSON-RPC request, its result is passed to `tx` once Moonraker responds
+    fn send_request(
+        &self,
+        method: &str,
+        params: Payload,
+        tx: Sender<RpcResult>,
+    ) -> Result<(), ezsockets::Error> {
+        let next_id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
+
+        let mut request = JsonRPCRequest::new(method, next_id);
+        request.params = params;
+        self.state.register(next_id, tx);
+        self.handle.text(serde_json::to_string(&request)?)?;
+
+        Ok(())
     }
 
     async fn process_call_response(&self, response: serde_json::Value) {
-        let conn_id = response.get("id").and_then(|v| v.as_u64());
-        if let Some(conn_id) = conn_id {
-            if let Some((_, tx)) = self.state.requests.remove(&conn_id) {
-                if let Err(msg) = tx.send(response) {
-                    eprintln!("Error returning response for {}: {:?}", conn_id, msg)
-                }
+        let response: JsonRPCResponse = match serde_json::from_value(response) {
+            Ok(response) => response,
+            Err(err) => {
+                tracing::warn!("Malformed response from Moonraker: {}", err);
+                return;
+            }
+        };
+        let conn_id = response.id;
+        let result = response.into_result();
+        if let Err(error) = &result {
+            tracing::warn!(
+                id = conn_id,
+                code = error.code,
+                "Moonraker request failed: {}",
+                error.message
+            );
+        }
+
+        if let Some((_, tx)) = self.state.requests.remove(&conn_id) {
+            if let Err(msg) = tx.send(result) {
+                eprintln!("Error returning response for {}: {:?}", conn_id, msg)
             }
         }
     }
@@ -98,6 +249,26 @@ impl Client {
                 Some("notify_klippy_disconnected") => {
                     Some(MoonrakerStatusNotification::KlippyDisconnected)
                 }
+                Some("notify_active_spool_set") => {
+                    Some(MoonrakerStatusNotification::ActiveSpoolSet(payload))
+                }
+                Some("notify_gcode_response") => payload
+                    .pointer("/0")
+                    .and_then(|v| v.as_str())
+                    .map(|response| {
+                        MoonrakerStatusNotification::GcodeResponse(response.to_owned())
+                    }),
+                Some("notify_announcement_update") | Some("notify_announcement_dismissed") => {
+                    Some(MoonrakerStatusNotification::AnnouncementsChanged)
+                }
+                Some("notify_power_changed") => Some(MoonrakerStatusNotification::PowerChanged),
+                Some("notify_history_changed") => Some(MoonrakerStatusNotification::HistoryChanged),
+                Some("notify_filelist_changed") => {
+                    Some(MoonrakerStatusNotification::FileListChanged(payload))
+                }
+                Some("notify_cpu_throttled") => {
+                    Some(MoonrakerStatusNotification::CpuThrottled(payload))
+                }
                 Some(method) => {
                     // notify_sensor_update
                     // notify_service_state_changed
@@ -144,33 +315,73 @@ impl ezsockets::ClientExt for Client {
     async fn on_call(&mut self, call: Self::Call) -> anyhow::Result<(), ezsockets::Error> {
         match call {
             MoonrakerCommands::GetObjectList(tx) => {
-                let next_id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
-
-                let request = JsonRPCRequest::new("printer.objects.list", next_id);
-                self.state.requests.insert(next_id, tx);
-                self.handle.text(serde_json::to_string(&request)?)?;
+                self.send_request("printer.objects.list", json!({}), tx)
+            }
+            MoonrakerCommands::GetServerInfo(tx) => self.send_request("server.info", json!({}), tx),
+            MoonrakerCommands::GetSystemInfo(tx) => {
+                self.send_request("machine.system_info", json!({}), tx)
+            }
+            MoonrakerCommands::GetProcStats(tx) => {
+                self.send_request("machine.proc_stats", json!({}), tx)
+            }
+            MoonrakerCommands::GetUpdateStatus(tx) => {
+                self.send_request("machine.update.status", json!({}), tx)
+            }
+            MoonrakerCommands::ListPowerDevices(tx) => {
+                self.send_request("machine.device_power.devices", json!({}), tx)
+            }
+            MoonrakerCommands::GetHistoryTotals(tx) => {
+                self.send_request("server.history.totals", json!({}), tx)
+            }
+            MoonrakerCommands::QueryEndstops(tx) => {
+                self.send_request("printer.query_endstops.status", json!({}), tx)
+            }
+            // Monitors (e.g. MCU load) are not needed and only add to the response size
+            MoonrakerCommands::GetTemperatureStore(tx) => self.send_request(
+                "server.temperature_store",
+                json!({ "include_monitors": false }),
+                tx,
+            ),
+            MoonrakerCommands::GetPrinterInfo(tx) => {
+                self.send_request("printer.info", json!({}), tx)
             }
             MoonrakerCommands::Subscribe((tx, objects)) => {
-                let next_id = self.state.next_id.fetch_add(1, Ordering::Relaxed);
                 let wanted = objects
                     .iter()
                     .map(|v| (v, None))
                     .collect::<HashMap<_, Option<Vec<String>>>>();
-                let mut request = JsonRPCRequest::new("printer.objects.subscribe", next_id);
-                request.params = json!({
-                    "objects": wanted,
-                });
-                self.state.requests.insert(next_id, tx);
-                self.handle.text(serde_json::to_string(&request)?)?;
+                self.send_request(
+                    "printer.objects.subscribe",
+                    json!({ "objects": wanted }),
+                    tx,
+                )
+            }
+            MoonrakerCommands::GetActiveSpool(tx) => {
+                self.send_request("server.spoolman.get_spool_id", json!({}), tx)
+            }
+            MoonrakerCommands::GetFileMetadata((tx, filename)) => {
+                self.send_request("server.files.metadata", json!({ "filename": filename }), tx)
+            }
+            MoonrakerCommands::GetSpool((tx, spool_id)) => self.send_request(
+                "server.spoolman.proxy",
+                json!({
+                    "request_method": "GET",
+                    "path": format!("/v1/spool/{spool_id}"),
+                }),
+                tx,
+            ),
+            MoonrakerCommands::ListAnnouncements(tx) => {
+                self.send_request("server.announcements.list", json!({}), tx)
             }
         }
-        Ok(())
     }
 
     /// Called when the client successfully connected (or reconnected).
     ///
     /// Returning an error will force-close the client.
     async fn on_connect(&mut self) -> Result<(), Error> {
+        self.failed_attempts = 0;
+        self.stats.connected();
         if let Err(err) = self
             .updates
             .send(MoonrakerStatusNotification::MoonrakerConnected)
@@ -181,6 +392,30 @@ impl ezsockets::ClientExt for Client {
         Ok(())
     }
 
+    /// Called when the client fails a connection/reconnection attempt.
+    ///
+    /// ezsockets waits `reconnect_interval` before retrying, the time exceeding
+    /// it as the delay grows is waited for here.
+    async fn on_connect_fail(&mut self, error: WSError) -> Result<ClientCloseMode, Error> {
+        self.failed_attempts += 1;
+        if let Some(max_attempts) = self.reconnect.max_attempts {
+            if self.failed_attempts >= max_attempts {
+                tracing::error!(
+                    attempts = self.failed_attempts,
+                    "Giving up connecting to Moonraker: {}",
+                    error
+                );
+                return Ok(ClientCloseMode::Close);
+            }
+        }
+
+        let delay = self.reconnect.delay(self.failed_attempts);
+        tracing::debug!("Reconnecting to Moonraker in {:?}", delay);
+        tokio::time::sleep(delay.saturating_sub(self.reconnect.interval)).await;
+
+        Ok(ClientCloseMode::Reconnect)
+    }
+
     /// Called when the connection is closed by the server.
     ///
     /// Returning an error will force-close the client.
@@ -189,6 +424,9 @@ impl ezsockets::ClientExt for Client {
     ///
     /// For reconnections, use `ClientConfig::reconnect_interval`.
     async fn on_close(&mut self, _frame: Option<CloseFrame>) -> Result<ClientCloseMode, Error> {
+        // Requests sent on this connection are never answered after a reconnect
+        self.state.abandon_requests();
+        self.stats.disconnected();
         if let Err(err) = self
             .updates
             .send(MoonrakerStatusNotification::MoonrakerDisconnected)
@@ -207,6 +445,8 @@ impl ezsockets::ClientExt for Client {
     ///
     /// For reconnections, use `ClientConfig::reconnect_interval`.
     async fn on_disconnect(&mut self) -> Result<ClientCloseMode, Error> {
+        self.state.abandon_requests();
+        self.stats.disconnected();
         if let Err(err) = self
             .updates
             .send(MoonrakerStatusNotification::MoonrakerDisconnected)
@@ -218,3 +458,57 @@ impl ezsockets::ClientExt for Client {
         Ok(ClientCloseMode::Reconnect)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    fn state() -> MoonrakerClientState {
+        MoonrakerClientState {
+            requests: DashMap::new(),
+            next_id: AtomicU64::new(0),
+        }
+    }
+
+    #[test]
+    fn requests_given_up_on_are_discarded() {
+        let state = state();
+        let (tx, rx) = oneshot::channel();
+        state.register(0, tx);
+        drop(rx);
+
+        let (tx, _rx) = oneshot::channel();
+        state.register(1, tx);
+
+        assert!(!state.requests.contains_key(&0));
+        assert!(state.requests.contains_key(&1));
+    }
+
+    #[tokio::test]
+    async fn pending_requests_fail_on_disconnect() {
+        let state = state();
+        let (tx, rx) = oneshot::channel();
+        state.register(0, tx);
+
+        state.abandon_requests();
+
+        let result = tokio::time::timeout(std::time::Duration::from_millis(100), rx).await;
+        assert!(matches!(result, Ok(Err(_))), "{result:?}");
+        assert!(state.requests.is_empty());
+    }
+
+    #[test]
+    fn lost_connections_are_counted_once() {
+        let stats = ConnectionStats::default();
+        stats.connected();
+        assert!(stats.connected_since.load(Ordering::Relaxed) > 0);
+
+        stats.disconnected();
+        stats.disconnected();
+
+        assert!(!stats.connected.load(Ordering::Relaxed));
+        assert_eq!(stats.connected_since.load(Ordering::Relaxed), 0);
+        assert_eq!(stats.reconnects.load(Ordering::Relaxed), 1);
+    }
+}
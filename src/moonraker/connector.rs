@@ -0,0 +1,182 @@
+use anyhow::{anyhow, bail, Context};
+use ezsockets::client::ClientConnector;
+use ezsockets::ClientConfig;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::Connector;
+use url::Url;
+
+/// Settings for establishing the websocket connection to Moonraker
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ConnectOptions {
+    /// API key sent when connecting to Moonraker
+    pub api_key: Option<String>,
+    /// PEM encoded CA certificate trusted in addition to the system roots
+    pub ca_cert: Option<PathBuf>,
+    /// Accept any certificate and hostname presented by a `wss://` server
+    pub insecure_skip_verify: bool,
+    /// When to retry connecting after the connection failed or was lost
+    pub reconnect: ReconnectPolicy,
+}
+
+/// Validate `--moonraker-url`, upgrading HTTP URLs to websocket URLs.
+///
+/// An empty path is completed to Moonraker's `/websocket` endpoint.
+pub(crate) fn websocket_url(url: &Url) -> anyhow::Result<Url> {
+    let mut url = url.clone();
+    let scheme = match url.scheme() {
+        "ws" | "wss" => None,
+        "http" => Some("ws"),
+        "https" => Some("wss"),
+        scheme => bail!(
+            "Unsupported Moonraker URL scheme `{scheme}`, use e.g. ws://{}:7125/websocket",
+            url.host_str().unwrap_or("127.0.0.1")
+        ),
+    };
+    if let Some(scheme) = scheme {
+        tracing::warn!(
+            "Moonraker URL uses `{}`, connecting with `{scheme}` instead",
+            url.scheme()
+        );
+        url.set_scheme(scheme)
+            .map_err(|_| anyhow!("Failed to change the scheme of {url}"))?;
+    }
+    if matches!(url.path(), "" | "/") {
+        url.set_path("/websocket");
+    }
+
+    Ok(url)
+}
+
+/// Upper bound of the delay between two connection attempts
+const RECONNECT_BACKOFF_MAX: Duration = Duration::from_secs(300);
+
+/// How to retry connecting to Moonraker after the connection failed or was lost
+#[derive(Clone, Debug)]
+pub(crate) struct ReconnectPolicy {
+    /// Delay before the first retry, doubled with every failed attempt
+    pub interval: Duration,
+    /// Consecutive failed attempts after which to give up, `None` retries forever
+    pub max_attempts: Option<u32>,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            interval: ezsockets::client::DEFAULT_RECONNECT_INTERVAL,
+            max_attempts: None,
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Delay before the next attempt after `failed_attempts` consecutive failures
+    pub fn delay(&self, failed_attempts: u32) -> Duration {
+        self.interval
+            .saturating_mul(2u32.saturating_pow(failed_attempts.saturating_sub(1)))
+            .min(RECONNECT_BACKOFF_MAX.max(self.interval))
+    }
+}
+
+impl ConnectOptions {
+    /// The TLS configuration to use, `None` if the defaults are sufficient
+    fn tls_connector(&self) -> anyhow::Result<Option<native_tls::TlsConnector>> {
+        if self.ca_cert.is_none() && !self.insecure_skip_verify {
+            return Ok(None);
+        }
+
+        let mut builder = native_tls::TlsConnector::builder();
+        if let Some(path) = &self.ca_cert {
+            let pem = std::fs::read(path)
+                .with_context(|| format!("Failed to read CA certificate {}", path.display()))?;
+            let certificate = native_tls::Certificate::from_pem(&pem)
+                .with_context(|| format!("Failed to parse CA certificate {}", path.display()))?;
+            builder.add_root_certificate(certificate);
+        }
+        if self.insecure_skip_verify {
+            tracing::warn!("TLS certificate verification is disabled");
+            builder
+                .danger_accept_invalid_certs(true)
+                .danger_accept_invalid_hostnames(true);
+        }
+
+        Ok(Some(builder.build().context("Failed to set up TLS")?))
+    }
+}
+
+/// [`ClientConnector`] for tokio using a custom TLS configuration for `wss://` URLs
+pub(crate) struct ClientConnectorTls {
+    handle: enfync::builtin::native::TokioHandle,
+    tls: Option<native_tls::TlsConnector>,
+}
+
+impl ClientConnectorTls {
+    pub fn new(options: &ConnectOptions) -> anyhow::Result<Self> {
+        Ok(Self {
+            handle: tokio::runtime::Handle::current().into(),
+            tls: options.tls_connector()?,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl ClientConnector for ClientConnectorTls {
+    type Handle = enfync::builtin::native::TokioHandle;
+    type Message = tungstenite::Message;
+    type WSError = tungstenite::Error;
+    type Socket = tokio_tungstenite::WebSocketStream<
+        tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>,
+    >;
+
+    fn handle(&self) -> Self::Handle {
+        self.handle.clone()
+    }
+
+    async fn connect(&self, config: &ClientConfig) -> Result<Self::Socket, Self::WSError> {
+        let request = config.connect_http_request();
+        let connector = self.tls.clone().map(Connector::NativeTls);
+
+        match tokio_tungstenite::connect_async_tls_with_config(request, None, false, connector)
+            .await
+        {
+            Ok((socket, _)) => Ok(socket),
+            // The details are lost once ezsockets converts the error, so report them here
+            Err(tungstenite::Error::Tls(err)) => {
+                tracing::error!(
+                    url = config.connect_url(),
+                    "TLS handshake with Moonraker failed, pass --ca-cert to trust a self-signed certificate: {}",
+                    err
+                );
+                Err(tungstenite::Error::Tls(err))
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn websocket(url: &str) -> anyhow::Result<String> {
+        websocket_url(&Url::parse(url)?).map(String::from)
+    }
+
+    #[test]
+    fn moonraker_urls_are_completed() {
+        assert_eq!(
+            websocket("ws://voron.local:7125/websocket").unwrap(),
+            "ws://voron.local:7125/websocket"
+        );
+        assert_eq!(
+            websocket("http://voron.local:7125").unwrap(),
+            "ws://voron.local:7125/websocket"
+        );
+        assert_eq!(
+            websocket("https://voron.local/moonraker/websocket").unwrap(),
+            "wss://voron.local/moonraker/websocket"
+        );
+        assert!(websocket("ftp://voron.local").is_err());
+    }
+}
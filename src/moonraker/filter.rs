@@ -0,0 +1,83 @@
+/// Selects the Klipper objects to subscribe to by their name, e.g. `temperature_sensor chamber`
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ObjectFilter {
+    /// Glob patterns of objects to subscribe to, all objects if empty
+    pub include: Vec<String>,
+    /// Glob patterns of objects to skip, takes precedence over `include`
+    pub exclude: Vec<String>,
+}
+
+impl ObjectFilter {
+    pub fn matches(&self, name: &str) -> bool {
+        let included =
+            self.include.is_empty() || self.include.iter().any(|pattern| glob(pattern, name));
+        included && !self.exclude.iter().any(|pattern| glob(pattern, name))
+    }
+}
+
+/// Match `text` against a glob `pattern` supporting `*` (any run of characters)
+/// and `?` (a single character)
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` and the text position it was tried at
+    let mut backtrack = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                // Let the last `*` consume one more character and try again
+                Some((star, consumed)) => {
+                    backtrack = Some((star, consumed + 1));
+                    p = star + 1;
+                    t = consumed + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob("temperature_sensor *", "temperature_sensor chamber"));
+        assert!(glob("tmc*", "tmc2209 stepper_x"));
+        assert!(glob("*_x", "tmc2209 stepper_x"));
+        assert!(glob("mcu", "mcu"));
+        assert!(glob("mc?", "mcu"));
+        assert!(glob("*", ""));
+        assert!(!glob("mcu", "mcu rpi"));
+        assert!(!glob("tmc*_y", "tmc2209 stepper_x"));
+        assert!(!glob("?", ""));
+    }
+
+    #[test]
+    fn exclude_takes_precedence_over_include() {
+        let filter = ObjectFilter {
+            include: vec!["temperature_sensor *".to_owned(), "extruder".to_owned()],
+            exclude: vec!["* raspberry_pi".to_owned()],
+        };
+
+        assert!(filter.matches("extruder"));
+        assert!(filter.matches("temperature_sensor chamber"));
+        assert!(!filter.matches("temperature_sensor raspberry_pi"));
+        assert!(!filter.matches("heater_bed"));
+        assert!(ObjectFilter::default().matches("heater_bed"));
+    }
+}
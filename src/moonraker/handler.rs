@@ -1,55 +1,170 @@
-use crate::moonraker::types::Payload;
-use crate::moonraker::{Client, MoonrakerCommands, MoonrakerStatusNotification};
+use crate::moonraker::types::{JsonRPCError, Payload, RpcResult};
+use crate::moonraker::{
+    Client, ConnectOptions, ConnectionStats, MoonrakerCommands, MoonrakerStatusNotification,
+    ObjectFilter,
+};
 
-use crate::types::{klipper, moonraker, MetricsExporter};
+use crate::recorder::GlobalLabels;
+use crate::types::{klipper, moonraker, CounterTracker, ExportContext, MetricsExporter};
 use anyhow::anyhow;
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+use metrics::{counter, gauge};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::json;
-use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use strum::EnumIter;
 use thiserror::Error;
 use tokio::sync::{mpsc, oneshot, Mutex};
+use tracing::Instrument;
 use url::Url;
 
+/// Global label identifying the printer, see `--instance-name`
+const INSTANCE_LABEL: &str = "instance";
+
 #[derive(Error, Debug)]
 pub(crate) enum UpdateHandlerError {
     #[error("Websocket update notification channel disconnected")]
     ChannelDisconnected,
     #[error("Update notification for `{0}` is not supported")]
     UnknownStatusUpdate(String),
-    #[error("Error deserializing stats data")]
-    DeserializationError(#[from] serde_json::Error),
+    #[error("Failed to deserialize '{object}': {source}")]
+    DeserializationError {
+        object: String,
+        source: serde_json::Error,
+    },
     #[error("Required field not found: `{0}`")]
     MissingStatsField(String),
     #[error("Fatal Moonraker connection error")]
     FatalMoonrakerConnectionError,
     #[error("HTTP Listener error")]
     HttpListenerError(#[from] std::io::Error),
+    #[error("Moonraker request failed ({code}): {message}")]
+    RequestFailed { code: i64, message: String },
+    #[error("Moonraker did not answer the request within {0:?}")]
+    RequestTimeout(Duration),
+}
+
+impl UpdateHandlerError {
+    /// Whether retrying the same request later can succeed.
+    ///
+    /// Moonraker answers with `503` while Klippy is not (yet) connected, e.g.
+    /// during a firmware restart, and may not answer at all if the connection
+    /// dropped mid-request. Anything else points at a protocol error.
+    fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            UpdateHandlerError::RequestFailed { code: 503, .. }
+                | UpdateHandlerError::RequestTimeout(_)
+        )
+    }
+}
+
+/// First delay between retries of the initial subscription, doubled on every attempt
+const SUBSCRIBE_RETRY_BACKOFF: Duration = Duration::from_secs(1);
+const SUBSCRIBE_RETRY_BACKOFF_MAX: Duration = Duration::from_secs(30);
+/// How long to wait for the close frame to be sent when shutting down
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Deserialize the status of a printer object, naming the object on failure
+fn deserialize_stats<T: DeserializeOwned>(
+    data_type: &StatusData,
+    data: &serde_json::Value,
+) -> Result<T, UpdateHandlerError> {
+    serde_json::from_value(data.to_owned()).map_err(|source| {
+        UpdateHandlerError::DeserializationError {
+            object: data_type.clone().into(),
+            source,
+        }
+    })
+}
+
+/// Export when Klipper last reported its status, so stale data can be alerted on
+/// with `time() - klipper_last_update_timestamp_seconds`
+fn record_update_time() {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    gauge!("klipper.last_update_timestamp_seconds").set(now.as_secs_f64());
+}
+
+/// Objects reported by Klipper, by how the exporter handles them
+#[derive(Debug, Default)]
+pub(crate) struct ObjectList {
+    /// Supported objects selected by `--include`, `--exclude` and `--gcode-macro-variables`
+    pub subscribed: Vec<String>,
+    /// Supported objects not selected by the filters
+    pub excluded: Vec<String>,
+    /// Objects the exporter has no metrics for
+    pub unsupported: Vec<String>,
+}
+
+impl From<JsonRPCError> for UpdateHandlerError {
+    fn from(error: JsonRPCError) -> Self {
+        UpdateHandlerError::RequestFailed {
+            code: error.code,
+            message: error.message,
+        }
+    }
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Hash)]
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq, PartialOrd, Hash, EnumIter)]
 enum StatusData {
+    Adxl345(String),
+    Announcements,
+    Beacon,
+    BedMesh,
+    BedScrews,
+    Cartographer,
     ControllerFan(String),
+    DisplayStatus,
+    Dotstar(String),
+    Endstops,
     ExcludeObject,
     Extruder(String),
+    ExtruderStepper(String),
     Fan(String),
     FanGeneric(String),
+    FileMetadata,
     FilamentMotionSensor(String),
     FilamentSwitchSensor(String),
+    FirmwareRetraction,
+    GcodeButton(String),
+    GcodeMacro(String),
     GCodeMove,
     HeaterBed(String),
     HeaterFan(String),
+    HeaterGeneric(String),
+    HistoryTotals,
+    IdleTimeout,
+    InputShaper,
+    Led(String),
+    LoadCell(String),
+    ManualProbe,
     Mcu(String),
     MoonrakerStatus,
     MotionReport,
+    Neopixel(String),
+    OutputPin(String),
     PauseResume,
+    PowerDevices,
     PrintStats,
+    PrinterInfo,
     Probe,
+    QuadGantryLevel,
+    ScrewsTiltAdjust,
+    SdcardLoop,
+    ServerInfo,
+    Servo(String),
+    Spoolman,
     StepperEnable,
+    SystemInfo,
     SystemStats,
     TemperatureFan(String),
     TemperatureSensor(String),
+    ThrottledState,
     TMC2130(String),
     TMC2208(String),
     TMC2209(String),
@@ -57,12 +172,43 @@ enum StatusData {
     TMC2660(String),
     TMC5160(String),
     Toolhead,
+    UpdateStatus,
     VirtualSdCard,
     Webhooks,
     ZThermalAdjust,
     ZTilt,
 }
 
+impl StatusData {
+    /// Whether the status is reported by Klipper, as opposed to Moonraker itself
+    fn is_klipper_object(&self) -> bool {
+        !matches!(
+            self,
+            StatusData::Announcements
+                | StatusData::Endstops
+                | StatusData::FileMetadata
+                | StatusData::HistoryTotals
+                | StatusData::MoonrakerStatus
+                | StatusData::PowerDevices
+                | StatusData::PrinterInfo
+                | StatusData::ServerInfo
+                | StatusData::Spoolman
+                | StatusData::SystemInfo
+                | StatusData::ThrottledState
+                | StatusData::UpdateStatus
+        )
+    }
+
+    /// The kind of object without its name, e.g. `extruder` for `extruder extruder1`
+    fn object_type(&self) -> String {
+        let name = String::from(self.clone());
+        match name.split_once(' ') {
+            Some((kind, _)) => kind.to_owned(),
+            None => name,
+        }
+    }
+}
+
 impl TryFrom<&str> for StatusData {
     type Error = UpdateHandlerError;
 
@@ -102,6 +248,7 @@ impl TryFrom<&str> for StatusData {
                 Ok(StatusData::FilamentSwitchSensor(name.to_owned()))
             }
             ("pause_resume", _) => Ok(StatusData::PauseResume),
+            ("sdcard_loop", _) => Ok(StatusData::SdcardLoop),
             ("probe", _) => Ok(StatusData::Probe),
             ("z_tilt", _) => Ok(StatusData::ZTilt),
             ("motion_report", _) => Ok(StatusData::MotionReport),
@@ -112,6 +259,30 @@ impl TryFrom<&str> for StatusData {
             ("virtual_sdcard", _) => Ok(StatusData::VirtualSdCard),
             ("system_stats", _) => Ok(StatusData::SystemStats),
             ("temperature_fan", Some(name)) => Ok(StatusData::TemperatureFan(name.to_owned())),
+            ("bed_mesh", _) => Ok(StatusData::BedMesh),
+            ("display_status", _) => Ok(StatusData::DisplayStatus),
+            ("idle_timeout", _) => Ok(StatusData::IdleTimeout),
+            ("firmware_retraction", _) => Ok(StatusData::FirmwareRetraction),
+            ("output_pin", Some(name)) => Ok(StatusData::OutputPin(name.to_owned())),
+            ("servo", Some(name)) => Ok(StatusData::Servo(name.to_owned())),
+            ("quad_gantry_level", _) => Ok(StatusData::QuadGantryLevel),
+            ("screws_tilt_adjust", _) => Ok(StatusData::ScrewsTiltAdjust),
+            ("heater_generic", Some(name)) => Ok(StatusData::HeaterGeneric(name.to_owned())),
+            ("extruder_stepper", Some(name)) => Ok(StatusData::ExtruderStepper(name.to_owned())),
+            ("led", Some(name)) => Ok(StatusData::Led(name.to_owned())),
+            ("neopixel", Some(name)) => Ok(StatusData::Neopixel(name.to_owned())),
+            ("dotstar", Some(name)) => Ok(StatusData::Dotstar(name.to_owned())),
+            ("gcode_button", Some(name)) => Ok(StatusData::GcodeButton(name.to_owned())),
+            ("gcode_macro", Some(name)) => Ok(StatusData::GcodeMacro(name.to_owned())),
+            ("manual_probe", _) => Ok(StatusData::ManualProbe),
+            ("bed_screws", _) => Ok(StatusData::BedScrews),
+            ("input_shaper", _) => Ok(StatusData::InputShaper),
+            ("adxl345", Some(name)) => Ok(StatusData::Adxl345(name.to_owned())),
+            ("adxl345", None) => Ok(StatusData::Adxl345("adxl345".to_owned())),
+            ("beacon", _) => Ok(StatusData::Beacon),
+            ("cartographer", _) => Ok(StatusData::Cartographer),
+            ("load_cell", Some(name)) => Ok(StatusData::LoadCell(name.to_owned())),
+            ("load_cell", None) => Ok(StatusData::LoadCell("load_cell".to_owned())),
             _ => Err(UpdateHandlerError::UnknownStatusUpdate(value.to_owned())),
         }
     }
@@ -129,11 +300,22 @@ impl From<StatusData> for String {
             }
             StatusData::Webhooks => String::from("webhooks"),
             StatusData::MoonrakerStatus => String::from("moonraker"),
+            StatusData::Spoolman => String::from("spoolman"),
+            StatusData::Announcements => String::from("announcements"),
+            StatusData::Endstops => String::from("endstops"),
+            StatusData::FileMetadata => String::from("file_metadata"),
+            StatusData::HistoryTotals => String::from("history_totals"),
+            StatusData::PowerDevices => String::from("power_devices"),
+            StatusData::PrinterInfo => String::from("printer_info"),
+            StatusData::ServerInfo => String::from("server_info"),
+            StatusData::SystemInfo => String::from("system_info"),
+            StatusData::ThrottledState => String::from("throttled_state"),
+            StatusData::UpdateStatus => String::from("update_status"),
             StatusData::Extruder(name) => {
                 if name == "extruder" {
                     String::from("extruder")
                 } else {
-                    format!("extruderÏ {name}")
+                    format!("extruder {name}")
                 }
             }
             StatusData::HeaterBed(name) => {
@@ -183,6 +365,7 @@ impl From<StatusData> for String {
                 format!("filament_motion_sensor {name}")
             }
             StatusData::PauseResume => String::from("pause_resume"),
+            StatusData::SdcardLoop => String::from("sdcard_loop"),
             StatusData::Probe => String::from("probe"),
             StatusData::ZTilt => String::from("z_tilt"),
             StatusData::MotionReport => String::from("motion_report"),
@@ -192,310 +375,2103 @@ impl From<StatusData> for String {
             StatusData::PrintStats => String::from("print_stats"),
             StatusData::VirtualSdCard => String::from("virtual_sdcard"),
             StatusData::SystemStats => String::from("system_stats"),
+            StatusData::BedMesh => String::from("bed_mesh"),
+            StatusData::DisplayStatus => String::from("display_status"),
             StatusData::TemperatureFan(name) => {
                 format!("temperature_fan {name}")
             }
+            StatusData::IdleTimeout => String::from("idle_timeout"),
+            StatusData::FirmwareRetraction => String::from("firmware_retraction"),
+            StatusData::OutputPin(name) => {
+                format!("output_pin {name}")
+            }
+            StatusData::Servo(name) => {
+                format!("servo {name}")
+            }
+            StatusData::QuadGantryLevel => String::from("quad_gantry_level"),
+            StatusData::ScrewsTiltAdjust => String::from("screws_tilt_adjust"),
+            StatusData::HeaterGeneric(name) => {
+                format!("heater_generic {name}")
+            }
+            StatusData::ExtruderStepper(name) => {
+                format!("extruder_stepper {name}")
+            }
+            StatusData::Led(name) => {
+                format!("led {name}")
+            }
+            StatusData::Neopixel(name) => {
+                format!("neopixel {name}")
+            }
+            StatusData::Dotstar(name) => {
+                format!("dotstar {name}")
+            }
+            StatusData::GcodeButton(name) => {
+                format!("gcode_button {name}")
+            }
+            StatusData::GcodeMacro(name) => {
+                format!("gcode_macro {name}")
+            }
+            StatusData::ManualProbe => String::from("manual_probe"),
+            StatusData::BedScrews => String::from("bed_screws"),
+            StatusData::InputShaper => String::from("input_shaper"),
+            StatusData::Adxl345(name) => {
+                if name == "adxl345" {
+                    String::from("adxl345")
+                } else {
+                    format!("adxl345 {name}")
+                }
+            }
+            StatusData::Beacon => String::from("beacon"),
+            StatusData::Cartographer => String::from("cartographer"),
+            StatusData::LoadCell(name) => {
+                if name == "load_cell" {
+                    String::from("load_cell")
+                } else {
+                    format!("load_cell {name}")
+                }
+            }
         }
     }
 }
 
+/// Lifecycle of Klippy as announced by Moonraker, exported as `klipper.klippy.state`
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u8)]
+enum KlippyLifecycle {
+    Disconnected = 0,
+    Ready = 1,
+    Shutdown = 2,
+}
+
+/// The last known status of every printer object
+type Status = DashMap<StatusData, serde_json::Value>;
+
 #[derive(Debug)]
 pub struct UpdateHandler {
     initialized: AtomicBool,
+    klippy_state: AtomicU8,
     updates: Mutex<mpsc::Receiver<MoonrakerStatusNotification>>,
     connection: Arc<ezsockets::Client<Client>>,
+    connection_stats: Arc<ConnectionStats>,
     url: Url,
-    current_status: DashMap<StatusData, serde_json::Value>,
+    current_status: Status,
+    gcode_move: klipper::GCodeMoveTracker,
+    heater_overshoot: klipper::HeaterOvershootTracker,
+    probe_accuracy: klipper::ProbeAccuracyTracker,
+    webhooks: klipper::WebhooksTracker,
+    /// Kept across reconnects, a restart of Klipper shows as a decrease of its counters
+    counters: CounterTracker,
+    display_message: klipper::DisplayMessageTracker,
+    input_shaper: klipper::InputShaperTracker,
+    printer_info: klipper::PrinterInfoTracker,
+    print_stats: klipper::PrintStatsTracker,
+    system_info: moonraker::SystemInfoTracker,
+    network_interfaces: moonraker::NetworkInterfaceTracker,
+    /// Set once the announcements endpoint turned out to be unavailable
+    announcements_unavailable: AtomicBool,
+    /// Metadata of every file printed since connecting, by file name
+    file_metadata: DashMap<String, Payload>,
+    /// Objects reported by Klipper the exporter has no metrics for
+    unsupported_objects: DashSet<String>,
+    /// Objects whose last export failed, they are only warned about once
+    failing_objects: DashSet<StatusData>,
+    update_manager_unavailable: AtomicBool,
+    labels: GlobalLabels,
+    options: UpdateHandlerOptions,
+}
+
+/// Settings controlling what the [`UpdateHandler`] fetches from Moonraker
+#[derive(Clone, Debug, Default)]
+pub(crate) struct UpdateHandlerOptions {
+    /// Export the active Spoolman spool
+    pub spoolman: bool,
+    /// Export the number of unread Moonraker announcements
+    pub announcements: bool,
+    /// Subscribe to `gcode_macro` objects to export their numeric variables
+    pub gcode_macro_variables: bool,
+    /// Explicitly configured instance name, takes precedence over the printer hostname
+    pub instance_name: Option<String>,
+    /// How often to retry listing and subscribing to objects after a transient failure
+    pub subscribe_retries: u32,
+    /// How long to wait for Moonraker to answer a request
+    pub request_timeout: Duration,
+    /// Export missing optional values as NaN instead of skipping the metric
+    pub null_as_nan: bool,
+    /// Maximum deviation in °C from the target temperature still considered "at target"
+    pub heater_deadband: f64,
+    /// Record MCU round trip times as histograms, see `--mcu-rtt-histogram`
+    pub mcu_rtt_histogram: bool,
+    /// How to connect to Moonraker
+    pub connection: ConnectOptions,
+    /// The Klipper objects to subscribe to
+    pub objects: ObjectFilter,
 }
 
 impl UpdateHandler {
     pub async fn new(
         url: &Url,
-        // objects: Option<Vec<String>>,
+        labels: GlobalLabels,
+        options: UpdateHandlerOptions,
     ) -> anyhow::Result<(
         Self,
         impl std::future::Future<Output = std::result::Result<(), ezsockets::Error>>,
     )> {
         let (tx, rx) = tokio::sync::mpsc::channel(100);
-        let (handle, future) = Client::connect(url.as_str(), tx.clone()).await?;
+        let connection_stats = Arc::new(ConnectionStats::default());
+        let (handle, future) = Client::connect(
+            url.as_str(),
+            &options.connection,
+            tx.clone(),
+            connection_stats.clone(),
+        )
+        .await?;
+
+        // Until the printer hostname is known fall back to the host we connect to
+        if let Some(instance) = options.instance_name.as_deref().or(url.host_str()) {
+            labels.insert(INSTANCE_LABEL, instance.to_owned());
+        }
 
         Ok((
             Self {
                 initialized: AtomicBool::new(false),
+                klippy_state: AtomicU8::new(KlippyLifecycle::Disconnected as u8),
                 updates: Mutex::new(rx),
                 connection: Arc::new(handle),
+                connection_stats,
                 url: url.to_owned(),
                 current_status: DashMap::new(),
+                gcode_move: klipper::GCodeMoveTracker::default(),
+                heater_overshoot: klipper::HeaterOvershootTracker::default(),
+                probe_accuracy: klipper::ProbeAccuracyTracker::default(),
+                webhooks: klipper::WebhooksTracker::default(),
+                counters: CounterTracker::default(),
+                display_message: klipper::DisplayMessageTracker::default(),
+                input_shaper: klipper::InputShaperTracker::default(),
+                printer_info: klipper::PrinterInfoTracker::default(),
+                print_stats: klipper::PrintStatsTracker::default(),
+                system_info: moonraker::SystemInfoTracker::default(),
+                network_interfaces: moonraker::NetworkInterfaceTracker::default(),
+                announcements_unavailable: AtomicBool::new(false),
+                file_metadata: DashMap::new(),
+                unsupported_objects: DashSet::new(),
+                failing_objects: DashSet::new(),
+                update_manager_unavailable: AtomicBool::new(false),
+                labels,
+                options,
             },
             future,
         ))
     }
 
     pub async fn export(&self) -> Result<(), UpdateHandlerError> {
+        self.span().in_scope(|| self.export_status())
+    }
+
+    /// Close the websocket connection to Moonraker, e.g. when the exporter shuts down
+    pub async fn close(&self) {
+        self.close_connection(ezsockets::CloseCode::Normal, "Exporter shutting down")
+            .await
+    }
+
+    /// Close the websocket connection for good, this ends the exporter
+    async fn close_connection(&self, code: ezsockets::CloseCode, reason: &str) {
+        let frame = ezsockets::CloseFrame {
+            code,
+            reason: String::from(reason),
+        };
+        let signal = match self.connection.close(Some(frame)) {
+            Ok(signal) => signal,
+            Err(err) => {
+                tracing::debug!("Connection to Moonraker already closed: {}", err);
+                return;
+            }
+        };
+
+        // Give the close frame a chance to be sent before the runtime shuts down
+        let deadline = tokio::time::Instant::now() + CLOSE_TIMEOUT;
+        while signal.status() == ezsockets::MessageStatus::Sending
+            && tokio::time::Instant::now() < deadline
+        {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    }
+
+    /// Span attaching the printer instance to all events of this handler
+    fn span(&self) -> tracing::Span {
+        let instance = self.labels.get(INSTANCE_LABEL).unwrap_or_default();
+        tracing::info_span!("printer", instance = %instance)
+    }
+
+    fn export_status(&self) -> Result<(), UpdateHandlerError> {
+        // Exported before anything else so it is present even without any status
+        let up = self.initialized.load(Ordering::Relaxed);
+        gauge!("klipper.up").set(up as u8 as f64);
+        gauge!("klipper.klippy.state").set(self.klippy_state.load(Ordering::Relaxed) as f64);
+        self.connection_stats.export();
+        for name in self.unsupported_objects.iter() {
+            gauge!("mamalluca.unsupported_object", "name" => name.to_owned()).set(1.0);
+        }
+
         let current_status = self.current_status.clone().into_read_only();
+        let mut correlated = klipper::CorrelatedStats::default();
         for (data_type, data) in current_status.iter() {
-            let mut name = None;
-            let exporter: Box<dyn MetricsExporter> = match data_type {
-                StatusData::Mcu(identifier) => {
-                    name.replace(identifier);
-                    let data = data.pointer("/last_stats").ok_or(
-                        UpdateHandlerError::MissingStatsField(format!(
-                            "mcu.{identifier}.last_stats"
-                        )),
-                    )?;
-                    let data: klipper::McuStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
+            // A single object with an unexpected schema must not hide all other metrics
+            match self.export_object(data_type, data, &mut correlated) {
+                Ok(()) => {
+                    if self.failing_objects.remove(data_type).is_some() {
+                        tracing::info!(
+                            "Exporting object again: {}",
+                            String::from(data_type.clone())
+                        );
+                    }
                 }
-                StatusData::Webhooks => {
-                    let data: klipper::WebhooksStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
+                Err(err) => {
+                    if self.failing_objects.insert(data_type.clone()) {
+                        tracing::warn!("Skipping object in export: {}", err);
+                    } else {
+                        tracing::debug!("Skipping object in export: {}", err);
+                    }
+                    counter!("mamalluca.export_errors_total", "object_type" => data_type.object_type())
+                        .increment(1);
                 }
-                StatusData::MoonrakerStatus => {
-                    tracing::debug!(key = "moonraker", "Processing status update");
-                    let data = data
-                        .pointer("/0")
+            }
+        }
+
+        self.failing_objects
+            .retain(|kind| current_status.contains_key(kind));
+        correlated.export();
+
+        Ok(())
+    }
+
+    /// Export the metrics of a single printer object
+    fn export_object(
+        &self,
+        data_type: &StatusData,
+        data: &serde_json::Value,
+        correlated: &mut klipper::CorrelatedStats,
+    ) -> Result<(), UpdateHandlerError> {
+        let mut name = None;
+        let exporter: Box<dyn MetricsExporter> = match data_type {
+            StatusData::Mcu(identifier) => {
+                name.replace(identifier);
+                // Secondary MCUs may report before their first stats are available
+                let Some(data) = data.pointer("/last_stats") else {
+                    tracing::debug!(mcu = identifier, "No stats reported yet, skipping MCU");
+                    return Ok(());
+                };
+                let data: klipper::McuStats = deserialize_stats(data_type, data)?;
+                correlated.mcus.insert(identifier.to_owned(), data.clone());
+                Box::new(data)
+            }
+            StatusData::Webhooks => {
+                let data: klipper::WebhooksStats = deserialize_stats(data_type, data)?;
+                self.webhooks.export();
+                Box::new(data)
+            }
+            StatusData::MoonrakerStatus => {
+                tracing::debug!(key = "moonraker", "Processing status update");
+                let data = data
+                    .pointer("/0")
+                    .ok_or(UpdateHandlerError::MissingStatsField(
+                        "moonraker.status".to_string(),
+                    ))?;
+                let data: moonraker::MoonrakerStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::Announcements => {
+                let data: moonraker::AnnouncementsStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::PrinterInfo => {
+                let data: klipper::PrinterInfoStats = deserialize_stats(data_type, data)?;
+                self.printer_info.export();
+                Box::new(data)
+            }
+            StatusData::Endstops => {
+                let data: klipper::EndstopsStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::FileMetadata => {
+                let data: moonraker::FileMetadataStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::HistoryTotals => {
+                let data: moonraker::HistoryTotalsStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::PowerDevices => {
+                let data: moonraker::PowerDevicesStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::ServerInfo => {
+                let data: moonraker::ServerInfoStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::SystemInfo => {
+                let data =
+                    data.pointer("/system_info")
                         .ok_or(UpdateHandlerError::MissingStatsField(
-                            "moonraker.status".to_string(),
+                            "machine.system_info".to_string(),
                         ))?;
-                    let data: moonraker::MoonrakerStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::Extruder(identifier) => {
-                    name.replace(identifier);
-                    let data: klipper::ExtruderStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::HeaterBed(identifier) => {
-                    name.replace(identifier);
-                    let data: klipper::HeaterBedStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::TemperatureSensor(identifier) => {
-                    name.replace(identifier);
-                    let data: klipper::TemperatureSensorStats =
-                        serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::ControllerFan(identifier) => {
-                    name.replace(identifier);
-                    let data: klipper::GenericFanStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::TMC2130(identifier)
-                | StatusData::TMC2208(identifier)
-                | StatusData::TMC2209(identifier)
-                | StatusData::TMC2240(identifier)
-                | StatusData::TMC2660(identifier)
-                | StatusData::TMC5160(identifier) => {
-                    name.replace(identifier);
-                    let data: klipper::TMCStepperMotorDriver =
-                        serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::StepperEnable => {
-                    let data: klipper::StepperEnableStats =
-                        serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::Fan(identifier)
-                | StatusData::FanGeneric(identifier)
-                | StatusData::HeaterFan(identifier) => {
-                    name.replace(identifier);
-                    let data: klipper::GenericFanStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::ZThermalAdjust => {
-                    let data: klipper::ZThermalAdjustStats =
-                        serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::FilamentMotionSensor(identifier)
-                | StatusData::FilamentSwitchSensor(identifier) => {
-                    name.replace(identifier);
+                let data: moonraker::SystemInfoStats = deserialize_stats(data_type, data)?;
+                self.system_info.export();
+                Box::new(data)
+            }
+            StatusData::ThrottledState => {
+                let data: moonraker::ThrottledStateStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::UpdateStatus => {
+                let data: moonraker::UpdateStatusStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::Spoolman => {
+                let data: moonraker::SpoolmanStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::Extruder(identifier) => {
+                name.replace(identifier);
+                let data: klipper::ExtruderStats = deserialize_stats(data_type, data)?;
+                self.heater_overshoot.export(identifier);
+                Box::new(data)
+            }
+            StatusData::HeaterBed(identifier) => {
+                name.replace(identifier);
+                let data: klipper::HeaterBedStats = deserialize_stats(data_type, data)?;
+                self.heater_overshoot.export(identifier);
+                Box::new(data)
+            }
+            StatusData::TemperatureSensor(identifier) => {
+                name.replace(identifier);
+                let data: klipper::TemperatureSensorStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::ControllerFan(identifier) => {
+                name.replace(identifier);
+                let data: klipper::GenericFanStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::TMC2130(identifier)
+            | StatusData::TMC2208(identifier)
+            | StatusData::TMC2209(identifier)
+            | StatusData::TMC2240(identifier)
+            | StatusData::TMC2660(identifier)
+            | StatusData::TMC5160(identifier) => {
+                name.replace(identifier);
+                let mut data: klipper::TMCStepperMotorDriver = deserialize_stats(data_type, data)?;
+                data.reports_stallguard =
+                    !matches!(data_type, StatusData::TMC2208(_) | StatusData::TMC2209(_));
+                Box::new(data)
+            }
+            StatusData::StepperEnable => {
+                let data: klipper::StepperEnableStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::Fan(identifier)
+            | StatusData::FanGeneric(identifier)
+            | StatusData::HeaterFan(identifier) => {
+                name.replace(identifier);
+                let data: klipper::GenericFanStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::ZThermalAdjust => {
+                let data: klipper::ZThermalAdjustStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::FilamentMotionSensor(identifier)
+            | StatusData::FilamentSwitchSensor(identifier) => {
+                name.replace(identifier);
 
-                    let data: klipper::FilamentRunoutSensorStats =
-                        serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::PauseResume => {
-                    let data: klipper::PauseResumeStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::Probe => {
-                    let data: klipper::ProbeStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::ZTilt => {
-                    let data: klipper::ZTiltStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::MotionReport => {
-                    let data: klipper::MotionReportStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::ExcludeObject => {
-                    let data: klipper::ExcludeObjectStats =
-                        serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::Toolhead => {
-                    let data: klipper::ToolheadStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::GCodeMove => {
-                    let data: klipper::GCodeMoveStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::PrintStats => {
-                    let data: klipper::PrintStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::VirtualSdCard => {
-                    let data: klipper::VirtualSdCardStats =
-                        serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::SystemStats => {
-                    let data: klipper::SystemStats = serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-                StatusData::TemperatureFan(identifier) => {
-                    name.replace(identifier);
+                let data: klipper::FilamentRunoutSensorStats = deserialize_stats(data_type, data)?;
+                correlated.runout_sensors.push(data.clone());
+                Box::new(data)
+            }
+            StatusData::PauseResume => {
+                let data: klipper::PauseResumeStats = deserialize_stats(data_type, data)?;
+                correlated.pause_resume.replace(data.clone());
+                Box::new(data)
+            }
+            StatusData::SdcardLoop => {
+                let data: klipper::SdcardLoopStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::Probe => {
+                let data: klipper::ProbeStats = deserialize_stats(data_type, data)?;
+                self.probe_accuracy.export(&data.name);
+                Box::new(data)
+            }
+            StatusData::ZTilt => {
+                let data: klipper::ZTiltStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::MotionReport => {
+                let data: klipper::MotionReportStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::ExcludeObject => {
+                let data: klipper::ExcludeObjectStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::Toolhead => {
+                let data: klipper::ToolheadStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::GCodeMove => {
+                let data: klipper::GCodeMoveStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::PrintStats => {
+                let data: klipper::PrintStats = deserialize_stats(data_type, data)?;
+                self.print_stats.export();
+                correlated.print_stats.replace(data.clone());
+                Box::new(data)
+            }
+            StatusData::VirtualSdCard => {
+                let data: klipper::VirtualSdCardStats = deserialize_stats(data_type, data)?;
+                correlated.virtual_sdcard.replace(data.clone());
+                Box::new(data)
+            }
+            StatusData::SystemStats => {
+                let data: klipper::SystemStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::TemperatureFan(identifier) => {
+                name.replace(identifier);
 
-                    let data: klipper::TemperatureFanStats =
-                        serde_json::from_value(data.to_owned())?;
-                    Box::new(data)
-                }
-            };
-            exporter.export(name)
+                let data: klipper::TemperatureFanStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::BedMesh => {
+                let data: klipper::BedMeshStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::DisplayStatus => {
+                let data: klipper::DisplayStatusStats = deserialize_stats(data_type, data)?;
+                self.display_message.export();
+                Box::new(data)
+            }
+            StatusData::IdleTimeout => {
+                let data: klipper::IdleTimeoutStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::FirmwareRetraction => {
+                let data: klipper::FirmwareRetractionStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::OutputPin(identifier) => {
+                name.replace(identifier);
+                let data: klipper::OutputPinStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::Servo(identifier) => {
+                name.replace(identifier);
+                let data: klipper::ServoStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::QuadGantryLevel => {
+                let data: klipper::QuadGantryLevelStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::ScrewsTiltAdjust => {
+                let data: klipper::ScrewsTiltAdjustStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::HeaterGeneric(identifier) => {
+                name.replace(identifier);
+                let data: klipper::HeaterGenericStats = deserialize_stats(data_type, data)?;
+                self.heater_overshoot.export(identifier);
+                Box::new(data)
+            }
+            StatusData::ExtruderStepper(identifier) => {
+                name.replace(identifier);
+                let data: klipper::ExtruderStepperStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::Dotstar(identifier)
+            | StatusData::Led(identifier)
+            | StatusData::Neopixel(identifier) => {
+                name.replace(identifier);
+                let data: klipper::LedStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::GcodeButton(identifier) => {
+                name.replace(identifier);
+                let data: klipper::GcodeButtonStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::GcodeMacro(identifier) => {
+                name.replace(identifier);
+                let data: klipper::GcodeMacroStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::ManualProbe => {
+                let data: klipper::ManualProbeStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::BedScrews => {
+                let data: klipper::BedScrewsStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::InputShaper => {
+                let data: klipper::InputShaperStats = deserialize_stats(data_type, data)?;
+                self.input_shaper.export();
+                Box::new(data)
+            }
+            StatusData::Adxl345(identifier) => {
+                name.replace(identifier);
+                let data: klipper::Adxl345Stats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+            StatusData::Beacon | StatusData::Cartographer => {
+                let mut data: klipper::EddyProbeStats = deserialize_stats(data_type, data)?;
+                data.probe = data_type.object_type();
+                Box::new(data)
+            }
+            StatusData::LoadCell(identifier) => {
+                name.replace(identifier);
+                let data: klipper::LoadCellStats = deserialize_stats(data_type, data)?;
+                Box::new(data)
+            }
+        };
+        exporter.export(&self.export_context(name.map(String::as_str)));
+
+        Ok(())
+    }
+
+    fn export_context<'a>(&self, name: Option<&'a str>) -> ExportContext<'a> {
+        ExportContext {
+            name,
+            null_as_nan: self.options.null_as_nan,
+            heater_deadband: self.options.heater_deadband,
+        }
+    }
+
+    /// Feed the trackers with an updated object.
+    ///
+    /// Counters and trackers depend on every update rather than the state
+    /// at export time, e.g. the peak temperature of a heater or a counter
+    /// reset in between two scrapes. Scrapes only export their current values.
+    fn observe_object(
+        &self,
+        data_type: &StatusData,
+        data: &serde_json::Value,
+    ) -> Result<(), UpdateHandlerError> {
+        let object = String::from(data_type.to_owned());
+        match data_type {
+            StatusData::Mcu(identifier) => {
+                let Some(data) = data.pointer("/last_stats") else {
+                    return Ok(());
+                };
+                let data: klipper::McuStats = deserialize_stats(data_type, data)?;
+                self.counters.observe(
+                    &object,
+                    data.counters(&self.export_context(Some(identifier))),
+                );
+            }
+            StatusData::Webhooks => {
+                let data: klipper::WebhooksStats = deserialize_stats(data_type, data)?;
+                self.webhooks.observe(&data);
+            }
+            StatusData::MoonrakerStatus => {
+                let data = data
+                    .pointer("/0")
+                    .ok_or(UpdateHandlerError::MissingStatsField(
+                        "moonraker.status".to_string(),
+                    ))?;
+                let data: moonraker::MoonrakerStats = deserialize_stats(data_type, data)?;
+                self.network_interfaces.observe(&data);
+                self.counters
+                    .observe(&object, data.counters(&self.export_context(None)));
+            }
+            StatusData::PrinterInfo => {
+                let data: klipper::PrinterInfoStats = deserialize_stats(data_type, data)?;
+                self.printer_info.observe(&data);
+            }
+            StatusData::SystemInfo => {
+                let data =
+                    data.pointer("/system_info")
+                        .ok_or(UpdateHandlerError::MissingStatsField(
+                            "machine.system_info".to_string(),
+                        ))?;
+                let data: moonraker::SystemInfoStats = deserialize_stats(data_type, data)?;
+                self.system_info.observe(&data);
+            }
+            StatusData::HistoryTotals => {
+                let data: moonraker::HistoryTotalsStats = deserialize_stats(data_type, data)?;
+                self.counters
+                    .observe(&object, data.counters(&self.export_context(None)));
+            }
+            StatusData::Extruder(identifier) => {
+                let data: klipper::ExtruderStats = deserialize_stats(data_type, data)?;
+                self.heater_overshoot.observe(identifier, &data);
+            }
+            StatusData::HeaterBed(identifier) => {
+                let data: klipper::HeaterBedStats = deserialize_stats(data_type, data)?;
+                self.heater_overshoot.observe(identifier, &data);
+            }
+            StatusData::HeaterGeneric(identifier) => {
+                let data: klipper::HeaterGenericStats = deserialize_stats(data_type, data)?;
+                self.heater_overshoot.observe(identifier, &data);
+            }
+            StatusData::Toolhead => {
+                let data: klipper::ToolheadStats = deserialize_stats(data_type, data)?;
+                self.counters
+                    .observe(&object, data.counters(&self.export_context(None)));
+            }
+            StatusData::GCodeMove => {
+                let data: klipper::GCodeMoveStats = deserialize_stats(data_type, data)?;
+                self.gcode_move.observe(&data);
+            }
+            StatusData::PrintStats => {
+                let data: klipper::PrintStats = deserialize_stats(data_type, data)?;
+                self.print_stats.observe(&data);
+            }
+            StatusData::DisplayStatus => {
+                let data: klipper::DisplayStatusStats = deserialize_stats(data_type, data)?;
+                self.display_message.observe(&data);
+            }
+            StatusData::InputShaper => {
+                let data: klipper::InputShaperStats = deserialize_stats(data_type, data)?;
+                self.input_shaper.observe(&data);
+            }
+            _ => {}
         }
+
         Ok(())
     }
 
+    /// [`Self::observe_object`] for every object of a status update or snapshot
+    fn observe_objects<'a>(&self, keys: impl IntoIterator<Item = &'a String>) {
+        for key in keys {
+            let Ok(data_type) = StatusData::try_from(key.as_str()) else {
+                continue;
+            };
+            let Some(status) = self.current_status.get(&data_type).map(|s| s.clone()) else {
+                continue;
+            };
+            // Failing objects are reported by the export
+            if let Err(err) = self.observe_object(&data_type, &status) {
+                tracing::debug!(key, "Not tracking object: {}", err);
+            }
+        }
+    }
+
+    /// Store the status of a Moonraker object and feed its trackers
+    fn set_status(&self, data_type: StatusData, value: serde_json::Value) {
+        if let Err(err) = self.observe_object(&data_type, &value) {
+            tracing::debug!(
+                object = String::from(data_type.to_owned()),
+                "Not tracking object: {}",
+                err
+            );
+        }
+        self.current_status.insert(data_type, value);
+    }
+
     pub async fn process(&self) -> Result<(), UpdateHandlerError> {
         let updates = &mut self.updates.lock().await;
 
         while let Some(ref notification) = updates.recv().await {
-            let result = match notification {
-                MoonrakerStatusNotification::MoonrakerConnected => {
-                    self.on_moonraker_connected().await
-                }
-                MoonrakerStatusNotification::MoonrakerDisconnected => {
-                    self.on_moonraker_disconnected().await
-                }
-                MoonrakerStatusNotification::KlipperStatusData(payload) => {
-                    self.process_status_update(payload).await
-                }
-                MoonrakerStatusNotification::MoonrakerStatusData(payload) => {
-                    self.current_status
-                        .insert(StatusData::MoonrakerStatus, payload.to_owned());
+            self.process_notification(notification)
+                .instrument(self.span())
+                .await;
+        }
+
+        Err(UpdateHandlerError::ChannelDisconnected)
+    }
+
+    async fn process_notification(&self, notification: &MoonrakerStatusNotification) {
+        let result = match notification {
+            MoonrakerStatusNotification::MoonrakerConnected => self.on_moonraker_connected().await,
+            MoonrakerStatusNotification::MoonrakerDisconnected => {
+                self.on_moonraker_disconnected().await
+            }
+            MoonrakerStatusNotification::KlipperStatusData(payload) => {
+                self.process_status_update(payload).await
+            }
+            MoonrakerStatusNotification::MoonrakerStatusData(payload) => {
+                self.set_status(StatusData::MoonrakerStatus, payload.to_owned());
+                Ok(())
+            }
+            MoonrakerStatusNotification::ActiveSpoolSet(payload) => {
+                if self.options.spoolman {
+                    let spool_id = payload.pointer("/0/spool_id").and_then(|v| v.as_u64());
+                    self.set_active_spool(spool_id).await
+                } else {
                     Ok(())
                 }
-
-                n => {
-                    tracing::info!(
-                        "Implementation required for notification {}, {:#?}",
-                        n,
-                        &notification,
-                    );
+            }
+            MoonrakerStatusNotification::KlippyReady => self.on_klippy_ready().await,
+            MoonrakerStatusNotification::KlippyShutdown => self.on_klippy_shutdown().await,
+            MoonrakerStatusNotification::KlippyDisconnected => self.on_klippy_disconnected().await,
+            MoonrakerStatusNotification::PowerChanged => self.refresh_power_devices().await,
+            MoonrakerStatusNotification::HistoryChanged => self.refresh_history_totals().await,
+            MoonrakerStatusNotification::FileListChanged(payload) => {
+                self.on_file_list_changed(payload);
+                Ok(())
+            }
+            MoonrakerStatusNotification::CpuThrottled(payload) => match payload.pointer("/0") {
+                Some(state) => self.set_throttled_state(state),
+                None => Ok(()),
+            },
+            MoonrakerStatusNotification::GcodeResponse(response) => {
+                self.on_gcode_response(response);
+                Ok(())
+            }
+            MoonrakerStatusNotification::AnnouncementsChanged => {
+                if self.options.announcements {
+                    self.refresh_announcements().await
+                } else {
                     Ok(())
                 }
-            };
-            if let Err(err) = result {
-                tracing::error!(
-                    "Processing status notification {} failed: {}",
-                    &notification,
-                    err
-                );
             }
+        };
+        if let Err(err) = result {
+            tracing::error!(
+                "Processing status notification {} failed: {}",
+                &notification,
+                err
+            );
         }
-
-        Err(UpdateHandlerError::ChannelDisconnected)
     }
 
     async fn process_status_update(&self, payload: &Payload) -> anyhow::Result<()> {
-        if !payload.is_array() {
-            anyhow::bail!("Malformed Klipper status update {:?}", payload);
-        }
-
-        if let Some(updates) = payload.as_array() {
-            for update in updates {
-                if let Some(update) = update.as_object() {
-                    for (key, patch) in update {
-                        let kind: StatusData = key.as_str().try_into()?;
-                        // TODO: Separate into generic updatables and transformers...
-                        tracing::debug!(key, "Processing status update");
-                        let mut entry = self.current_status.entry(kind).or_insert(json!({}));
-                        json_patch::merge(&mut entry, patch);
+        self.apply_status_update(payload)?;
+        self.refresh_file_metadata().await;
+        Ok(())
+    }
+
+    /// Merge a status update into the current status and feed the trackers
+    fn apply_status_update(&self, payload: &Payload) -> anyhow::Result<()> {
+        let result = merge_status_update(&self.current_status, payload);
+        if let Some(UpdateHandlerError::UnknownStatusUpdate(_)) =
+            result.as_ref().err().and_then(|err| err.downcast_ref())
+        {
+            counter!("mamalluca.unknown_objects_total").increment(1);
+        }
+        if result.is_ok() {
+            record_update_time();
+            if self.options.mcu_rtt_histogram {
+                self.record_mcu_rtt(payload);
+            }
+            let keys = payload
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(|update| update.as_object())
+                .flat_map(|update| update.keys());
+            self.observe_objects(keys);
+        }
+        result
+    }
+
+    /// Look up the slicer estimates once the printed file changes
+    async fn refresh_file_metadata(&self) {
+        let filename = self
+            .current_status
+            .get(&StatusData::PrintStats)
+            .and_then(|status| {
+                let filename = status.pointer("/filename")?.as_str()?;
+                (!filename.is_empty()).then(|| filename.to_owned())
+            });
+        let Some(filename) = filename else {
+            self.current_status.remove(&StatusData::FileMetadata);
+            return;
+        };
+        let current = self
+            .current_status
+            .get(&StatusData::FileMetadata)
+            .is_some_and(|status| {
+                status.pointer("/filename").and_then(|v| v.as_str()) == Some(&filename)
+            });
+        if current || !self.connection_stats.is_connected() {
+            return;
+        }
+
+        let cached = self
+            .file_metadata
+            .get(&filename)
+            .map(|entry| entry.to_owned());
+        let metadata = match cached {
+            Some(metadata) => metadata,
+            None => {
+                let command = |tx| MoonrakerCommands::GetFileMetadata((tx, filename.clone()));
+                let metadata = match self.request(command).await {
+                    Ok(Ok(metadata)) => metadata,
+                    // Remember the file anyway, e.g. if it was deleted while printing
+                    Ok(Err(err)) => {
+                        tracing::debug!(
+                            filename,
+                            "No metadata for the printed file: {}",
+                            UpdateHandlerError::from(err)
+                        );
+                        json!({ "filename": filename })
+                    }
+                    Err(err) => {
+                        tracing::warn!(filename, "Failed to query the file metadata: {}", err);
+                        return;
                     }
+                };
+                self.file_metadata
+                    .insert(filename.clone(), metadata.clone());
+                metadata
+            }
+        };
+        self.set_status(StatusData::FileMetadata, metadata);
+    }
+
+    /// Forget the metadata of changed files, e.g. a file sliced again under the same name
+    fn on_file_list_changed(&self, payload: &Payload) {
+        let item = payload.pointer("/0/item");
+        let root = item.and_then(|item| item.pointer("/root")?.as_str());
+        let Some(path) = item.and_then(|item| item.pointer("/path")?.as_str()) else {
+            return;
+        };
+        if root != Some("gcodes") {
+            return;
+        }
+
+        self.file_metadata.remove(path);
+        self.current_status
+            .remove_if(&StatusData::FileMetadata, |_, status| {
+                status.pointer("/filename").and_then(|v| v.as_str()) == Some(path)
+            });
+    }
+
+    /// Record the round trip times of every MCU whose stats are part of a status update
+    fn record_mcu_rtt(&self, payload: &Payload) {
+        let patches = payload
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|update| update.as_object())
+            .flatten();
+        for (key, patch) in patches {
+            let Ok(StatusData::Mcu(name)) = StatusData::try_from(key.as_str()) else {
+                continue;
+            };
+            if patch.get("last_stats").is_none() {
+                continue;
+            }
+            let Some(status) = self.current_status.get(&StatusData::Mcu(name.clone())) else {
+                continue;
+            };
+            let stats = status.pointer("/last_stats").cloned();
+            match stats.map(serde_json::from_value::<klipper::McuStats>) {
+                Some(Ok(stats)) => stats.record_rtt(&name),
+                Some(Err(err)) => {
+                    tracing::debug!(key, "Not recording MCU round trip times: {}", err)
                 }
+                None => {}
             }
         }
+    }
 
+    /// Replace all stored Klipper status with the full snapshot returned by a subscription
+    async fn set_initial_status(&self, snapshot: &Payload) -> anyhow::Result<()> {
+        self.current_status
+            .retain(|kind, _| !kind.is_klipper_object());
+        set_status_snapshot(&self.current_status, snapshot)?;
+        record_update_time();
+        self.counters.retain(|object| {
+            StatusData::try_from(object).is_ok_and(|kind| {
+                !kind.is_klipper_object() || self.current_status.contains_key(&kind)
+            })
+        });
+        self.observe_objects(
+            snapshot
+                .as_object()
+                .into_iter()
+                .flat_map(|objects| objects.keys()),
+        );
+        self.refresh_file_metadata().await;
         Ok(())
     }
-    fn build_channel(
+
+    /// Send a request to Moonraker and wait at most `request_timeout` for its result
+    async fn request(
         &self,
-    ) -> (
-        oneshot::Sender<serde_json::Value>,
-        oneshot::Receiver<serde_json::Value>,
-    ) {
-        oneshot::channel()
+        command: impl FnOnce(oneshot::Sender<RpcResult>) -> MoonrakerCommands,
+    ) -> anyhow::Result<RpcResult> {
+        let (tx, rx) = oneshot::channel();
+        self.connection.call(command(tx))?;
+
+        // Dropping the receiver on timeout lets the client discard the pending request
+        let timeout = self.options.request_timeout;
+        let result = tokio::time::timeout(timeout, rx)
+            .await
+            .map_err(|_| UpdateHandlerError::RequestTimeout(timeout))??;
+
+        Ok(result)
     }
 
     async fn on_moonraker_connected(&self) -> anyhow::Result<()> {
         tracing::info!(url = &self.url.to_string(), "Connected to Moonraker");
-        let objects = self.get_object_list().await?;
-        self.subscribe(objects).await?;
+
+        let subscription = self.refresh_subscription_with_retries().await;
+
+        // Optional information must not keep the printer metrics from being exported
+        let refreshes = [
+            ("server info", self.refresh_server_info().await),
+            ("temperature store", self.seed_temperatures().await),
+            ("system info", self.refresh_system_info().await),
+            ("throttled state", self.refresh_throttled_state().await),
+            ("update status", self.refresh_update_status().await),
+            ("power devices", self.refresh_power_devices().await),
+            ("history totals", self.refresh_history_totals().await),
+        ];
+        for (name, result) in refreshes {
+            if let Err(err) = result {
+                tracing::warn!("Failed to refresh the {}: {}", name, err);
+            }
+        }
+
+        if self.options.spoolman {
+            if let Err(err) = self.refresh_active_spool().await {
+                tracing::warn!("Failed to refresh the active spool: {}", err);
+            }
+        }
+
+        if self.options.announcements {
+            if let Err(err) = self.refresh_announcements().await {
+                tracing::warn!("Failed to refresh the announcements: {}", err);
+            }
+        }
+
+        subscription
+    }
+
+    /// Klipper (re)started while Moonraker stayed connected, e.g. after a
+    /// `FIRMWARE_RESTART`. The available objects may have changed, so the
+    /// subscription has to be set up again.
+    async fn on_klippy_ready(&self) -> anyhow::Result<()> {
+        tracing::info!("Klippy is ready, refreshing subscription");
+        let subscription = self.refresh_subscription_with_retries().await;
+        if let Err(err) = self.refresh_server_info().await {
+            tracing::warn!("Failed to refresh the server info: {}", err);
+        }
+
+        subscription
+    }
+
+    fn set_klippy_state(&self, state: KlippyLifecycle) {
+        self.klippy_state.store(state as u8, Ordering::Relaxed);
+    }
+
+    /// Count the console output of Klipper, responses starting with `!!` are errors
+    /// such as `!! Probe triggered prior to movement`
+    fn on_gcode_response(&self, response: &str) {
+        counter!("klipper.gcode_responses_total").increment(1);
+        if response.starts_with("!!") {
+            tracing::warn!("Klipper reported an error: {}", response);
+            counter!("klipper.gcode_errors_total").increment(1);
+        }
+        if let Some(z_result) = klipper::probe_result(response) {
+            let name = self
+                .current_status
+                .get(&StatusData::Probe)
+                .and_then(|status| Some(status.pointer("/name")?.as_str()?.to_owned()));
+            self.probe_accuracy
+                .observe(name.as_deref().unwrap_or("probe"), z_result);
+        }
+    }
+
+    /// Klipper stays connected to Moonraker after a shutdown and keeps reporting
+    /// its state, but temperatures and positions are no longer meaningful.
+    async fn on_klippy_shutdown(&self) -> anyhow::Result<()> {
+        tracing::warn!("Klippy shut down");
+        self.set_klippy_state(KlippyLifecycle::Shutdown);
+        self.gcode_move.reset();
+        self.heater_overshoot.reset();
+        self.probe_accuracy.reset();
+
+        Ok(())
+    }
+
+    /// Klipper went away while Moonraker stayed connected, drop everything it
+    /// reported until it is ready again.
+    async fn on_klippy_disconnected(&self) -> anyhow::Result<()> {
+        tracing::warn!("Klippy disconnected from Moonraker");
+        self.initialized.store(false, Ordering::Relaxed);
+        self.set_klippy_state(KlippyLifecycle::Disconnected);
+        self.current_status
+            .retain(|kind, _| !kind.is_klipper_object());
+        self.current_status.remove(&StatusData::Endstops);
+        self.current_status.remove(&StatusData::FileMetadata);
+        self.gcode_move.reset();
+        self.heater_overshoot.reset();
+        self.probe_accuracy.reset();
+        if let Err(err) = self.refresh_server_info().await {
+            tracing::warn!("Failed to refresh the server info: {}", err);
+        }
+
+        Ok(())
+    }
+
+    /// [`Self::refresh_subscription`], retried with backoff while Klippy is unavailable
+    async fn refresh_subscription_with_retries(&self) -> anyhow::Result<()> {
+        let mut attempt = 0;
+        while let Err(err) = self.refresh_subscription().await {
+            let transient = err
+                .downcast_ref::<UpdateHandlerError>()
+                .is_some_and(UpdateHandlerError::is_transient);
+            if !transient || attempt >= self.options.subscribe_retries {
+                // The connection is up but Moonraker stopped answering, e.g. a hung
+                // process. It would never recover, so leave restarting to the supervisor.
+                if let Some(UpdateHandlerError::RequestTimeout(_)) = err.downcast_ref() {
+                    tracing::error!(
+                        "Moonraker stopped answering requests, closing the connection: {}",
+                        err
+                    );
+                    self.close_connection(ezsockets::CloseCode::Away, "Moonraker not responding")
+                        .await;
+                }
+                return Err(err);
+            }
+
+            let delay = SUBSCRIBE_RETRY_BACKOFF
+                .saturating_mul(2u32.saturating_pow(attempt))
+                .min(SUBSCRIBE_RETRY_BACKOFF_MAX);
+            attempt += 1;
+            tracing::warn!(
+                attempt,
+                retries = self.options.subscribe_retries,
+                "Subscribing to Klipper objects failed, retrying in {:?}: {}",
+                delay,
+                err
+            );
+            tokio::time::sleep(delay).await;
+        }
 
         Ok(())
     }
 
+    /// Discover the available objects and subscribe to all supported ones
+    async fn refresh_subscription(&self) -> anyhow::Result<()> {
+        self.refresh_printer_info().await?;
+
+        let (objects, unsupported) = self.get_object_list().await?;
+        if !unsupported.is_empty() {
+            tracing::info!(
+                "Klipper reports objects the exporter does not support: {}",
+                unsupported.join(", ")
+            );
+        }
+        self.unsupported_objects.clear();
+        for name in unsupported {
+            self.unsupported_objects.insert(name);
+        }
+
+        let objects = objects
+            .into_iter()
+            .filter(|object| self.wanted(object))
+            .collect();
+        self.subscribe(objects).await
+    }
+
+    /// Whether a supported object is subscribed to with the configured filters
+    fn wanted(&self, object: &StatusData) -> bool {
+        self.options.objects.matches(&String::from(object.clone()))
+            && (self.options.gcode_macro_variables || !matches!(object, StatusData::GcodeMacro(_)))
+    }
+
+    /// Wait for the connection to Moonraker and list the objects Klipper reports
+    /// without subscribing to them, see `--dry-run`
+    pub async fn dry_run(&self) -> anyhow::Result<ObjectList> {
+        let updates = &mut self.updates.lock().await;
+        let connected = async {
+            while let Some(notification) = updates.recv().await {
+                if let MoonrakerStatusNotification::MoonrakerConnected = notification {
+                    return Ok(());
+                }
+            }
+            Err(UpdateHandlerError::ChannelDisconnected)
+        };
+        tokio::time::timeout(self.options.request_timeout, connected)
+            .await
+            .map_err(|_| {
+                anyhow!(
+                    "Not connected to Moonraker within {}s",
+                    self.options.request_timeout.as_secs()
+                )
+            })??;
+
+        let (supported, unsupported) = self.get_object_list().await?;
+        let (subscribed, excluded) = supported
+            .into_iter()
+            .partition::<Vec<_>, _>(|object| self.wanted(object));
+        let names = |objects: Vec<StatusData>| objects.into_iter().map(String::from).collect();
+
+        Ok(ObjectList {
+            subscribed: names(subscribed),
+            excluded: names(excluded),
+            unsupported,
+        })
+    }
+
     async fn on_moonraker_disconnected(&self) -> anyhow::Result<()> {
         tracing::warn!(url = &self.url.to_string(), "Disconnected from Moonraker");
         self.initialized.store(false, Ordering::Relaxed);
+        self.set_klippy_state(KlippyLifecycle::Disconnected);
         self.current_status.clear();
+        self.gcode_move.reset();
+        self.heater_overshoot.reset();
+        self.probe_accuracy.reset();
 
         Ok(())
     }
 
     async fn subscribe(&self, objects: Vec<StatusData>) -> anyhow::Result<()> {
-        let (tx, rx) = self.build_channel();
         let objects = objects
             .into_iter()
             .map(|i| i.into())
             .collect::<Vec<String>>();
-        self.connection
-            .call(MoonrakerCommands::Subscribe((tx, objects)))?;
 
-        let response = rx.await?;
+        let response = self
+            .request(|tx| MoonrakerCommands::Subscribe((tx, objects)))
+            .await?
+            .map_err(UpdateHandlerError::from)?;
         let updates = response
-            .pointer("/result/status")
+            .pointer("/status")
             .ok_or(anyhow!("Initial status updates not received"))?;
 
-        self.current_status.clear();
-        self.process_status_update(&json!([updates])).await?;
+        self.set_initial_status(updates).await?;
 
+        self.set_klippy_state(KlippyLifecycle::Ready);
         self.initialized.store(true, Ordering::Relaxed);
 
         Ok(())
     }
 
-    async fn get_object_list(&self) -> anyhow::Result<Vec<StatusData>> {
-        let (tx, rx) = self.build_channel();
-        self.connection.call(MoonrakerCommands::GetObjectList(tx))?;
-        let response = rx.await?;
-
-        Ok(response
-            .pointer("/result/objects")
-            .and_then(|v| v.as_array())
-            .map(|v| {
-                v.iter()
-                    .filter_map(|o| o.as_str())
-                    .map(|v| v.try_into())
-                    .filter_map(Result::ok)
-                    .collect::<Vec<StatusData>>()
+    /// Export the identity of the Klipper host and label metrics with its hostname
+    /// unless an instance name was configured
+    async fn refresh_printer_info(&self) -> anyhow::Result<()> {
+        // Keep the current instance label if the hostname is unavailable
+        let Ok(info) = self.request(MoonrakerCommands::GetPrinterInfo).await? else {
+            return Ok(());
+        };
+
+        if self.options.instance_name.is_none() {
+            if let Some(hostname) = info.pointer("/hostname").and_then(|v| v.as_str()) {
+                if self.labels.insert(INSTANCE_LABEL, hostname.to_owned()) {
+                    // The series of the previous instance are dropped, counters start over
+                    tracing::info!(instance = hostname, "Instance name changed");
+                    self.counters.retain(|_| false);
+                }
+            }
+        }
+        self.set_status(StatusData::PrinterInfo, info);
+
+        Ok(())
+    }
+
+    /// Export the health of Moonraker itself, e.g. warnings about deprecated options
+    async fn refresh_server_info(&self) -> anyhow::Result<()> {
+        match self.request(MoonrakerCommands::GetServerInfo).await? {
+            Ok(info) => {
+                self.set_status(StatusData::ServerInfo, info);
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to query the Moonraker server info: {}",
+                    UpdateHandlerError::from(err)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Export the identity of the Moonraker host, it does not change while connected
+    async fn refresh_system_info(&self) -> anyhow::Result<()> {
+        let mut info = match self.request(MoonrakerCommands::GetSystemInfo).await? {
+            Ok(info) => info,
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to query the Moonraker system info: {}",
+                    UpdateHandlerError::from(err)
+                );
+                return Ok(());
+            }
+        };
+
+        // The uptime keeps changing, the boot time derived from it does not
+        if let Ok(stats) = self.request(MoonrakerCommands::GetProcStats).await? {
+            let uptime = stats.pointer("/system_uptime").and_then(|v| v.as_f64());
+            let system_info = info
+                .pointer_mut("/system_info")
+                .and_then(|v| v.as_object_mut());
+            if let (Some(uptime), Some(system_info)) = (uptime, system_info) {
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default();
+                system_info.insert("boot_time".into(), json!(now.as_secs_f64() - uptime));
+            }
+        }
+
+        self.set_status(StatusData::SystemInfo, info);
+
+        Ok(())
+    }
+
+    /// Export whether a Raspberry Pi host is throttled, Moonraker only announces changes
+    async fn refresh_throttled_state(&self) -> anyhow::Result<()> {
+        match self.request(MoonrakerCommands::GetProcStats).await? {
+            Ok(stats) => match stats.pointer("/throttled_state") {
+                Some(state) => self.set_throttled_state(state)?,
+                None => tracing::debug!("Moonraker does not report a throttled state"),
+            },
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to query the Moonraker process stats: {}",
+                    UpdateHandlerError::from(err)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    fn set_throttled_state(&self, state: &Payload) -> anyhow::Result<()> {
+        let throttled: moonraker::ThrottledStateStats =
+            deserialize_stats(&StatusData::ThrottledState, state)?;
+        if !throttled.flags().is_empty() {
+            // Under-voltage is the most common cause of failed prints on a Raspberry Pi
+            tracing::warn!(flags = ?throttled.flags(), "Moonraker host is throttled");
+        }
+        self.set_status(StatusData::ThrottledState, state.to_owned());
+
+        Ok(())
+    }
+
+    /// Export which components have software updates pending, polled periodically
+    /// since Moonraker only checks for updates a few times a day
+    pub async fn refresh_update_status(&self) -> anyhow::Result<()> {
+        if !self.connection_stats.is_connected() {
+            return Ok(());
+        }
+
+        // The update_manager component is optional
+        match self.request(MoonrakerCommands::GetUpdateStatus).await? {
+            Ok(status) => {
+                self.set_status(StatusData::UpdateStatus, status);
+            }
+            Err(err) => {
+                if !self
+                    .update_manager_unavailable
+                    .swap(true, Ordering::Relaxed)
+                {
+                    tracing::warn!(
+                        "Moonraker update status is unavailable: {}",
+                        UpdateHandlerError::from(err)
+                    );
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Export whether the endstops are triggered, polled since they are not part of the
+    /// subscription. Querying them is skipped while printing to not delay the print.
+    pub async fn refresh_endstops(&self) -> anyhow::Result<()> {
+        let ready = self.klippy_state.load(Ordering::Relaxed) == KlippyLifecycle::Ready as u8;
+        if !self.connection_stats.is_connected() || !ready || self.is_printing() {
+            return Ok(());
+        }
+
+        match self.request(MoonrakerCommands::QueryEndstops).await? {
+            Ok(endstops) => {
+                self.set_status(StatusData::Endstops, endstops);
+            }
+            Err(err) => {
+                tracing::warn!(
+                    "Failed to query the endstops: {}",
+                    UpdateHandlerError::from(err)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether Klipper reports a print in progress
+    fn is_printing(&self) -> bool {
+        self.current_status
+            .get(&StatusData::PrintStats)
+            .is_some_and(|status| {
+                status.pointer("/state").and_then(|v| v.as_str()) == Some("printing")
             })
-            .unwrap_or_default())
+    }
+
+    /// Export the state of the `[power]` devices, e.g. smart plugs switching the printer
+    async fn refresh_power_devices(&self) -> anyhow::Result<()> {
+        // Only available if Moonraker's power component is configured
+        match self.request(MoonrakerCommands::ListPowerDevices).await? {
+            Ok(devices) => {
+                self.set_status(StatusData::PowerDevices, devices);
+            }
+            Err(err) => {
+                tracing::debug!(
+                    "Moonraker power devices are unavailable: {}",
+                    UpdateHandlerError::from(err)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Export the lifetime job statistics kept by Moonraker's history component
+    async fn refresh_history_totals(&self) -> anyhow::Result<()> {
+        match self.request(MoonrakerCommands::GetHistoryTotals).await? {
+            Ok(totals) => {
+                self.set_status(StatusData::HistoryTotals, totals);
+            }
+            Err(err) => {
+                tracing::debug!(
+                    "Moonraker job history is unavailable: {}",
+                    UpdateHandlerError::from(err)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Pre-populate heaters without a known status with the last temperatures buffered
+    /// by Moonraker, so they are exported while the subscription fails, e.g. while Klippy
+    /// is still starting. The snapshot of the next subscription replaces them.
+    async fn seed_temperatures(&self) -> anyhow::Result<()> {
+        match self.request(MoonrakerCommands::GetTemperatureStore).await? {
+            Ok(store) => seed_temperature_store(&self.current_status, &store),
+            Err(err) => {
+                tracing::debug!(
+                    "Moonraker temperature store is unavailable: {}",
+                    UpdateHandlerError::from(err)
+                );
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn refresh_announcements(&self) -> anyhow::Result<()> {
+        // Older Moonraker versions lack the announcements component, report no announcements
+        let status = match self.request(MoonrakerCommands::ListAnnouncements).await? {
+            Ok(status) => status,
+            Err(err) => {
+                if !self.announcements_unavailable.swap(true, Ordering::Relaxed) {
+                    tracing::warn!(
+                        "Moonraker announcements are unavailable: {}",
+                        UpdateHandlerError::from(err)
+                    );
+                }
+                json!({})
+            }
+        };
+        self.set_status(StatusData::Announcements, status);
+
+        Ok(())
+    }
+
+    async fn refresh_active_spool(&self) -> anyhow::Result<()> {
+        // Spoolman may be unreachable, report no active spool then
+        let spool_id = self
+            .request(MoonrakerCommands::GetActiveSpool)
+            .await?
+            .ok()
+            .and_then(|response| response.pointer("/spool_id").and_then(|v| v.as_u64()));
+        self.set_active_spool(spool_id).await
+    }
+
+    async fn set_active_spool(&self, spool_id: Option<u64>) -> anyhow::Result<()> {
+        let mut status = json!({ "spool_id": spool_id });
+
+        if let Some(spool_id) = spool_id {
+            let spool = self
+                .request(|tx| MoonrakerCommands::GetSpool((tx, spool_id)))
+                .await?;
+            if let Ok(spool) = spool {
+                status["spool"] = spool;
+            }
+        }
+
+        self.set_status(StatusData::Spoolman, status);
+
+        Ok(())
+    }
+
+    /// The objects reported by Klipper, split into supported ones and the names of
+    /// those the exporter does not know
+    async fn get_object_list(&self) -> anyhow::Result<(Vec<StatusData>, Vec<String>)> {
+        let response = self
+            .request(MoonrakerCommands::GetObjectList)
+            .await?
+            .map_err(UpdateHandlerError::from)?;
+
+        let mut supported = Vec::new();
+        let mut unsupported = Vec::new();
+        let objects = response.pointer("/objects").and_then(|v| v.as_array());
+        for name in objects.into_iter().flatten().filter_map(|o| o.as_str()) {
+            match StatusData::try_from(name) {
+                Ok(object) => supported.push(object),
+                Err(_) => unsupported.push(name.to_owned()),
+            }
+        }
+
+        Ok((supported, unsupported))
+    }
+}
+
+/// Apply incremental `notify_status_update` patches on top of the stored status.
+///
+/// Patches follow JSON merge semantics: fields missing from a patch keep their
+/// previous value and `null` fields are removed.
+fn merge_status_update(status: &Status, payload: &Payload) -> anyhow::Result<()> {
+    if !payload.is_array() {
+        anyhow::bail!("Malformed Klipper status update {:?}", payload);
+    }
+
+    if let Some(updates) = payload.as_array() {
+        for update in updates {
+            if let Some(update) = update.as_object() {
+                for (key, patch) in update {
+                    let kind: StatusData = match key.as_str().try_into() {
+                        Ok(kind) => kind,
+                        Err(err) => {
+                            tracing::trace!(
+                                key,
+                                fields = patch_schema(patch),
+                                "Unsupported object in status update"
+                            );
+                            return Err(err.into());
+                        }
+                    };
+                    // TODO: Separate into generic updatables and transformers...
+                    tracing::debug!(key, "Processing status update");
+                    let mut entry = status.entry(kind).or_insert_with(|| json!({}));
+                    json_patch::merge(&mut entry, patch);
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The fields of a status patch and their JSON types, e.g. `temperature: number`,
+/// to help mapping objects the exporter does not support yet
+fn patch_schema(patch: &Payload) -> String {
+    let Some(fields) = patch.as_object() else {
+        return String::new();
+    };
+    fields
+        .iter()
+        .map(|(name, value)| {
+            let kind = match value {
+                serde_json::Value::Null => "null",
+                serde_json::Value::Bool(_) => "bool",
+                serde_json::Value::Number(_) => "number",
+                serde_json::Value::String(_) => "string",
+                serde_json::Value::Array(_) => "array",
+                serde_json::Value::Object(_) => "object",
+            };
+            format!("{name}: {kind}")
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Store a full status snapshot, as returned by `printer.objects.subscribe`.
+///
+/// Unlike [`merge_status_update`] every object is stored verbatim, replacing
+/// any previous value including nested maps and `null` fields.
+fn set_status_snapshot(status: &Status, snapshot: &Payload) -> anyhow::Result<()> {
+    let objects = snapshot
+        .as_object()
+        .ok_or(anyhow!("Malformed Klipper status snapshot {:?}", snapshot))?;
+
+    for (key, value) in objects {
+        let kind: StatusData = key.as_str().try_into()?;
+        tracing::debug!(key, "Processing initial status");
+        status.insert(kind, value.to_owned());
+    }
+
+    Ok(())
+}
+
+/// Samples buffered by `server.temperature_store` for one sensor, oldest first
+#[derive(Debug, Deserialize)]
+struct TemperatureStoreEntry {
+    #[serde(default)]
+    temperatures: Vec<Option<f64>>,
+    #[serde(default)]
+    targets: Vec<Option<f64>>,
+    #[serde(default)]
+    powers: Vec<Option<f64>>,
+    #[serde(default)]
+    speeds: Vec<Option<f64>>,
+}
+
+/// Store the most recent buffered sample of every heater without a known status.
+///
+/// Only objects fully described by the buffered values are seeded, extruders and
+/// temperature sensors report fields the store does not keep.
+fn seed_temperature_store(status: &Status, store: &Payload) {
+    let Some(objects) = store.as_object() else {
+        return;
+    };
+    let last = |values: &[Option<f64>]| values.last().copied().flatten();
+
+    for (key, value) in objects {
+        let Ok(kind) = StatusData::try_from(key.as_str()) else {
+            continue;
+        };
+        if status.contains_key(&kind) {
+            continue;
+        }
+        // A single malformed sensor should not prevent seeding the others
+        let Ok(entry) = serde_json::from_value::<TemperatureStoreEntry>(value.to_owned()) else {
+            tracing::debug!(key, "Ignoring malformed temperature store entry");
+            continue;
+        };
+
+        let seeded = match (&kind, last(&entry.temperatures), last(&entry.targets)) {
+            (
+                StatusData::HeaterBed(_) | StatusData::HeaterGeneric(_),
+                Some(temperature),
+                Some(target),
+            ) => last(&entry.powers)
+                .map(|power| json!({"temperature": temperature, "target": target, "power": power})),
+            (StatusData::TemperatureFan(_), Some(temperature), Some(target)) => last(&entry.speeds)
+                .map(|speed| json!({"temperature": temperature, "target": target, "speed": speed})),
+            _ => None,
+        };
+        if let Some(seeded) = seeded {
+            tracing::debug!(key, "Seeding status from the temperature store");
+            status.insert(kind, seeded);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::moonraker::types::JsonRPCResponse;
+    use strum::IntoEnumIterator;
+
+    /// Expand a variant yielded by `EnumIter` (which carries an empty name)
+    /// into the objects Klipper would actually report for it.
+    fn samples(kind: StatusData) -> Vec<StatusData> {
+        let named = |f: fn(String) -> StatusData| vec![f("sample".to_owned())];
+
+        match kind {
+            StatusData::Mcu(_) => vec![
+                StatusData::Mcu("mcu".to_owned()),
+                StatusData::Mcu("rpi".to_owned()),
+            ],
+            StatusData::Extruder(_) => vec![
+                StatusData::Extruder("extruder".to_owned()),
+                StatusData::Extruder("extruder1".to_owned()),
+            ],
+            StatusData::HeaterBed(_) => vec![
+                StatusData::HeaterBed("heater_bed".to_owned()),
+                StatusData::HeaterBed("sample".to_owned()),
+            ],
+            StatusData::Adxl345(_) => vec![
+                StatusData::Adxl345("adxl345".to_owned()),
+                StatusData::Adxl345("bed".to_owned()),
+            ],
+            StatusData::LoadCell(_) => vec![
+                StatusData::LoadCell("load_cell".to_owned()),
+                StatusData::LoadCell("sample".to_owned()),
+            ],
+            // There is only ever a single part cooling fan
+            StatusData::Fan(_) => vec![StatusData::Fan("fan".to_owned())],
+            StatusData::ControllerFan(_) => named(StatusData::ControllerFan),
+            StatusData::FanGeneric(_) => named(StatusData::FanGeneric),
+            StatusData::FilamentMotionSensor(_) => named(StatusData::FilamentMotionSensor),
+            StatusData::FilamentSwitchSensor(_) => named(StatusData::FilamentSwitchSensor),
+            StatusData::HeaterFan(_) => named(StatusData::HeaterFan),
+            StatusData::TemperatureFan(_) => named(StatusData::TemperatureFan),
+            StatusData::TemperatureSensor(_) => named(StatusData::TemperatureSensor),
+            StatusData::TMC2130(_) => named(StatusData::TMC2130),
+            StatusData::TMC2208(_) => named(StatusData::TMC2208),
+            StatusData::TMC2209(_) => named(StatusData::TMC2209),
+            StatusData::TMC2240(_) => named(StatusData::TMC2240),
+            StatusData::TMC2660(_) => named(StatusData::TMC2660),
+            StatusData::TMC5160(_) => named(StatusData::TMC5160),
+            StatusData::OutputPin(_) => named(StatusData::OutputPin),
+            StatusData::Servo(_) => named(StatusData::Servo),
+            StatusData::HeaterGeneric(_) => named(StatusData::HeaterGeneric),
+            StatusData::ExtruderStepper(_) => named(StatusData::ExtruderStepper),
+            StatusData::Led(_) => named(StatusData::Led),
+            StatusData::Neopixel(_) => named(StatusData::Neopixel),
+            StatusData::Dotstar(_) => named(StatusData::Dotstar),
+            StatusData::GcodeButton(_) => named(StatusData::GcodeButton),
+            StatusData::GcodeMacro(_) => named(StatusData::GcodeMacro),
+            // Moonraker-side data and polled endstops never arrive via subscription
+            StatusData::Announcements
+            | StatusData::Endstops
+            | StatusData::FileMetadata
+            | StatusData::HistoryTotals
+            | StatusData::MoonrakerStatus
+            | StatusData::PowerDevices
+            | StatusData::PrinterInfo
+            | StatusData::ServerInfo
+            | StatusData::Spoolman
+            | StatusData::SystemInfo
+            | StatusData::ThrottledState
+            | StatusData::UpdateStatus => vec![],
+            kind @ (StatusData::Beacon
+            | StatusData::BedMesh
+            | StatusData::BedScrews
+            | StatusData::Cartographer
+            | StatusData::DisplayStatus
+            | StatusData::ExcludeObject
+            | StatusData::FirmwareRetraction
+            | StatusData::GCodeMove
+            | StatusData::IdleTimeout
+            | StatusData::InputShaper
+            | StatusData::ManualProbe
+            | StatusData::MotionReport
+            | StatusData::PauseResume
+            | StatusData::SdcardLoop
+            | StatusData::PrintStats
+            | StatusData::Probe
+            | StatusData::QuadGantryLevel
+            | StatusData::ScrewsTiltAdjust
+            | StatusData::StepperEnable
+            | StatusData::SystemStats
+            | StatusData::Toolhead
+            | StatusData::VirtualSdCard
+            | StatusData::Webhooks
+            | StatusData::ZThermalAdjust
+            | StatusData::ZTilt) => vec![kind],
+        }
+    }
+
+    #[test]
+    fn status_data_round_trips_through_object_name() {
+        for kind in StatusData::iter().flat_map(samples) {
+            let name = String::from(kind.clone());
+            let parsed = StatusData::try_from(name.as_str())
+                .unwrap_or_else(|e| panic!("`{name}` does not parse back: {e}"));
+
+            assert_eq!(parsed, kind, "`{name}` does not round-trip");
+        }
+    }
+
+    #[test]
+    fn object_name_round_trips_through_status_data() {
+        let names = StatusData::iter()
+            .flat_map(samples)
+            .map(String::from)
+            .chain(["extruder extruder1".to_owned()]);
+
+        for name in names {
+            let parsed = StatusData::try_from(name.as_str())
+                .unwrap_or_else(|e| panic!("`{name}` does not parse: {e}"));
+
+            assert_eq!(String::from(parsed), name, "`{name}` does not round-trip");
+        }
+    }
+
+    #[test]
+    fn status_snapshot_replaces_instead_of_merging() {
+        let stale = json!({ "steppers": { "stepper_x": true } });
+        let snapshot = json!({
+            "stepper_enable": { "steppers": { "stepper_y": false } },
+            "extruder": { "temperature": 21.5, "time_offset": null },
+        });
+
+        let merged = DashMap::new();
+        merged.insert(StatusData::StepperEnable, stale.clone());
+        merge_status_update(&merged, &json!([snapshot])).unwrap();
+
+        let replaced = DashMap::new();
+        replaced.insert(StatusData::StepperEnable, stale);
+        set_status_snapshot(&replaced, &snapshot).unwrap();
+
+        // Merging keeps steppers that are no longer part of the snapshot...
+        assert_eq!(
+            *merged.get(&StatusData::StepperEnable).unwrap(),
+            json!({ "steppers": { "stepper_x": true, "stepper_y": false } })
+        );
+        // ...and drops fields that are explicitly `null`
+        assert_eq!(
+            *merged
+                .get(&StatusData::Extruder("extruder".into()))
+                .unwrap(),
+            json!({ "temperature": 21.5 })
+        );
+
+        assert_eq!(
+            *replaced.get(&StatusData::StepperEnable).unwrap(),
+            json!({ "steppers": { "stepper_y": false } })
+        );
+        assert_eq!(
+            *replaced
+                .get(&StatusData::Extruder("extruder".into()))
+                .unwrap(),
+            json!({ "temperature": 21.5, "time_offset": null })
+        );
+    }
+
+    #[test]
+    fn temperature_store_seeds_unknown_heaters() {
+        let store = json!({
+            "heater_bed": {
+                "temperatures": [59.8, 60.1],
+                "targets": [60.0, 60.0],
+                "powers": [0.4, 0.35]
+            },
+            "heater_generic chamber": {
+                "temperatures": [40.0],
+                "targets": [45.0],
+                "powers": [1.0]
+            },
+            "temperature_fan exhaust": {
+                "temperatures": [35.5],
+                "targets": [40.0],
+                "speeds": [0.0]
+            },
+            "extruder": {
+                "temperatures": [210.0],
+                "targets": [210.0],
+                "powers": [0.5]
+            },
+            "temperature_sensor chamber": { "temperatures": [31.0] },
+            "unsupported_sensor foo": { "temperatures": [20.0] },
+            "heater_generic broken": { "temperatures": "n/a" }
+        });
+
+        let status = DashMap::new();
+        let chamber = StatusData::HeaterGeneric("chamber".into());
+        status.insert(chamber.clone(), json!({ "temperature": 44.9 }));
+        seed_temperature_store(&status, &store);
+
+        assert_eq!(
+            *status
+                .get(&StatusData::HeaterBed("heater_bed".into()))
+                .unwrap(),
+            json!({ "temperature": 60.1, "target": 60.0, "power": 0.35 })
+        );
+        assert_eq!(
+            *status
+                .get(&StatusData::TemperatureFan("exhaust".into()))
+                .unwrap(),
+            json!({ "temperature": 35.5, "target": 40.0, "speed": 0.0 })
+        );
+        // Live status is never replaced by buffered values
+        assert_eq!(
+            *status.get(&chamber).unwrap(),
+            json!({ "temperature": 44.9 })
+        );
+        assert_eq!(status.len(), 3);
+    }
+
+    /// Replay every `tests/fixtures/*.json` notification through the handler.
+    ///
+    /// Fixtures are captured `notify_status_update` notifications carrying the
+    /// complete state of the objects. The metric names listed in the `.metrics`
+    /// file of the same name, one per line, have to be exported.
+    #[tokio::test]
+    async fn status_fixtures_are_exported() {
+        let url = Url::parse("ws://127.0.0.1:1/websocket").unwrap();
+        let fixtures = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let mut paths = std::fs::read_dir(&fixtures)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert!(!paths.is_empty(), "No fixtures in {}", fixtures.display());
+
+        for path in paths {
+            let fixture = path.display();
+            let notification: Payload =
+                serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+            let (handler, _) =
+                UpdateHandler::new(&url, GlobalLabels::default(), Default::default())
+                    .await
+                    .unwrap();
+
+            // Counters are recorded with the update, the other metrics by the export
+            let output = crate::types::tests::render(|| {
+                handler
+                    .apply_status_update(&notification["params"])
+                    .unwrap_or_else(|err| panic!("{fixture}: {err:#}"));
+                handler.export_status().unwrap();
+            });
+
+            assert!(
+                !output.contains("mamalluca_export_errors_total"),
+                "{fixture}: export failed\n{output}"
+            );
+            let expected =
+                std::fs::read_to_string(path.with_extension("metrics")).unwrap_or_default();
+            for name in expected
+                .lines()
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+            {
+                let exported = output.lines().any(|line| {
+                    line.strip_prefix(name)
+                        .is_some_and(|rest| rest.starts_with([' ', '{']))
+                });
+                assert!(exported, "{fixture}: `{name}` not exported\n{output}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn trackers_observe_updates_between_exports() {
+        let url = Url::parse("ws://127.0.0.1:1/websocket").unwrap();
+        let (handler, _) = UpdateHandler::new(&url, GlobalLabels::default(), Default::default())
+            .await
+            .unwrap();
+        let extruder = json!({
+            "can_extrude": true,
+            "power": 0.0,
+            "pressure_advance": 0.04,
+            "smooth_time": 0.04,
+            "target": 0.0,
+            "temperature": 25.0,
+        });
+
+        for patch in [
+            extruder,
+            json!({"target": 200.0, "temperature": 190.0}),
+            json!({"temperature": 205.0}),
+            json!({"temperature": 199.0}),
+        ] {
+            handler
+                .apply_status_update(&json!([{ "extruder": patch }]))
+                .unwrap();
+        }
+        let output = crate::types::tests::render(|| handler.export_status().unwrap());
+
+        assert!(
+            output.contains("klipper_stats_heater_last_overshoot{name=\"extruder\"} 5"),
+            "{output}"
+        );
+    }
+
+    #[tokio::test]
+    async fn failing_objects_are_tracked_until_they_recover() {
+        let url = Url::parse("ws://127.0.0.1:1/websocket").unwrap();
+        let (handler, _) = UpdateHandler::new(&url, GlobalLabels::default(), Default::default())
+            .await
+            .unwrap();
+        let chamber = StatusData::TemperatureSensor("chamber".into());
+        handler.current_status.insert(chamber.clone(), json!({}));
+
+        let output = crate::types::tests::render(|| {
+            handler.export_status().unwrap();
+            handler.export_status().unwrap();
+        });
+        assert!(handler.failing_objects.contains(&chamber));
+        assert!(
+            output.contains("mamalluca_export_errors_total{object_type=\"temperature_sensor\"} 2"),
+            "{output}"
+        );
+
+        handler.current_status.insert(
+            chamber.clone(),
+            json!({"temperature": 30.0, "measured_min_temp": 20.0, "measured_max_temp": 31.0}),
+        );
+        handler.export_status().unwrap();
+        assert!(handler.failing_objects.is_empty());
+    }
+
+    /// Log output written to a shared buffer
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn export_events_carry_the_printer_instance() {
+        let url = Url::parse("ws://127.0.0.1:1/websocket").unwrap();
+        let options = UpdateHandlerOptions {
+            instance_name: Some("voron".into()),
+            ..Default::default()
+        };
+        let (handler, _) = UpdateHandler::new(&url, GlobalLabels::default(), options)
+            .await
+            .unwrap();
+        handler
+            .current_status
+            .insert(StatusData::TemperatureSensor("chamber".into()), json!({}));
+
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer({
+                let logs = logs.clone();
+                move || logs.clone()
+            })
+            .with_ansi(false)
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+        handler.export().await.unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        assert!(
+            logs.contains("printer{instance=voron}") && logs.contains("Skipping object in export"),
+            "{logs}"
+        );
+    }
+
+    #[tokio::test]
+    async fn klippy_transitions_survive_unanswered_server_info() {
+        // Answers everything but `server.info`, like a Moonraker busy with a slow component
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = Url::parse(&format!(
+            "ws://{}/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let requests = Arc::new(std::sync::Mutex::new(Vec::new()));
+        std::thread::spawn({
+            let requests = requests.clone();
+            move || {
+                let (stream, _) = listener.accept().unwrap();
+                let mut socket = tokio_tungstenite::tungstenite::accept(stream).unwrap();
+                while let Ok(message) = socket.read() {
+                    let Ok(request) =
+                        serde_json::from_slice::<serde_json::Value>(&message.into_data())
+                    else {
+                        continue;
+                    };
+                    let method = request["method"].as_str().unwrap_or_default().to_owned();
+                    requests.lock().unwrap().push(method.clone());
+                    let result = match method.as_str() {
+                        "server.info" => continue,
+                        "printer.info" => json!({"state": "ready", "hostname": "voron"}),
+                        "printer.objects.list" => json!({"objects": []}),
+                        "printer.objects.subscribe" => json!({"eventtime": 1.0, "status": {}}),
+                        _ => json!({}),
+                    };
+                    let reply = json!({"jsonrpc": "2.0", "id": request["id"], "result": result});
+                    if socket.send(reply.to_string().into()).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        let options = UpdateHandlerOptions {
+            request_timeout: Duration::from_millis(50),
+            ..Default::default()
+        };
+        let (handler, future) = UpdateHandler::new(&url, GlobalLabels::default(), options)
+            .await
+            .unwrap();
+        tokio::spawn(future);
+        while !handler.connection_stats.is_connected() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        handler.on_klippy_disconnected().await.unwrap();
+        handler.on_klippy_ready().await.unwrap();
+        assert!(handler.initialized.load(Ordering::Relaxed));
+        assert!(requests
+            .lock()
+            .unwrap()
+            .contains(&"printer.objects.subscribe".to_owned()));
+    }
+
+    #[tokio::test]
+    async fn unanswered_subscriptions_close_the_connection() {
+        // Accepts the websocket connection, but never answers a request
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = Url::parse(&format!(
+            "ws://{}/websocket",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let _socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            std::future::pending::<()>().await;
+        });
+
+        let options = UpdateHandlerOptions {
+            request_timeout: Duration::from_millis(50),
+            subscribe_retries: 0,
+            ..Default::default()
+        };
+        let (handler, future) = UpdateHandler::new(&url, GlobalLabels::default(), options)
+            .await
+            .unwrap();
+        let client = tokio::spawn(future);
+        while !handler.connection_stats.is_connected() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        let err = handler
+            .refresh_subscription_with_retries()
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.downcast_ref(),
+            Some(UpdateHandlerError::RequestTimeout(_))
+        ));
+        tokio::time::timeout(Duration::from_secs(5), client)
+            .await
+            .expect("client is still connected")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[test]
+    fn unsupported_patches_are_described_by_their_fields() {
+        let patch = json!({"temperature": 41.2, "state": "ok", "samples": [], "extra": null});
+        assert_eq!(
+            patch_schema(&patch),
+            "extra: null, samples: array, state: string, temperature: number"
+        );
+        assert_eq!(patch_schema(&json!(12)), "");
+    }
+
+    #[test]
+    fn deserialization_errors_name_the_object() {
+        let err = deserialize_stats::<klipper::TemperatureSensorStats>(
+            &StatusData::TemperatureSensor("chamber".into()),
+            &json!({}),
+        )
+        .unwrap_err();
+
+        assert!(err
+            .to_string()
+            .starts_with("Failed to deserialize 'temperature_sensor chamber': missing field"));
+    }
+
+    #[test]
+    fn error_responses_fail_the_request() {
+        let reply: JsonRPCResponse = serde_json::from_value(json!({
+            "jsonrpc": "2.0",
+            "id": 3,
+            "error": {"code": 503, "message": "Klippy Host not connected"},
+        }))
+        .unwrap();
+
+        let err = UpdateHandlerError::from(reply.into_result().unwrap_err());
+        assert_eq!(
+            err.to_string(),
+            "Moonraker request failed (503): Klippy Host not connected"
+        );
+        assert!(err.is_transient());
     }
 }
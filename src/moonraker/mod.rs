@@ -1,7 +1,14 @@
 mod client;
+mod connector;
+mod filter;
 mod handler;
 pub(crate) mod types;
 
-pub(crate) use handler::{UpdateHandler, UpdateHandlerError};
+pub(crate) use connector::{websocket_url, ConnectOptions, ReconnectPolicy};
+pub(crate) use filter::ObjectFilter;
+pub(crate) use handler::{UpdateHandler, UpdateHandlerError, UpdateHandlerOptions};
 pub(crate) use types::*;
-pub(crate) use {client::Client, client::MoonrakerCommands, client::MoonrakerStatusNotification};
+pub(crate) use {
+    client::Client, client::ConnectionStats, client::MoonrakerCommands,
+    client::MoonrakerStatusNotification,
+};
@@ -26,18 +26,30 @@ impl JsonRPCRequest {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct JsonRPCError {
-    code: usize,
-    message: String,
-    data: Payload,
+    /// JSON-RPC error code or the HTTP status of the failed request, e.g. `503`
+    pub code: i64,
+    pub message: String,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct JsonRPCResponse {
-    id: usize,
+    pub id: u64,
     result: Option<Payload>,
     error: Option<JsonRPCError>,
 }
 
+/// The result of a request to Moonraker
+pub(crate) type RpcResult = Result<Payload, JsonRPCError>;
+
+impl JsonRPCResponse {
+    pub fn into_result(self) -> RpcResult {
+        match self.error {
+            Some(error) => Err(error),
+            None => Ok(self.result.unwrap_or_default()),
+        }
+    }
+}
+
 // #[derive(Clone, Debug, Deserialize, Serialize)]
 // pub(crate) struct ObjectList {
 //     objects: Vec<String>,
@@ -0,0 +1,107 @@
+//! Rendering of the OpenMetrics text format from the Prometheus text format
+
+use std::collections::HashSet;
+
+pub(crate) const CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+/// Whether an `Accept` header value asks for the OpenMetrics text format
+pub(crate) fn accepted(accept: &str) -> bool {
+    accept.split(',').any(|media_range| {
+        let mut parts = media_range.split(';').map(str::trim);
+        let media_type = parts.next().unwrap_or_default();
+        let disabled = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f64>().ok())
+                .is_some_and(|q| q == 0.0)
+        });
+        media_type.eq_ignore_ascii_case("application/openmetrics-text") && !disabled
+    })
+}
+
+/// Convert the output of the Prometheus exporter to OpenMetrics.
+///
+/// Counter families are named without and their samples with a `_total`
+/// suffix, blank lines are not allowed and the exposition ends with `# EOF`.
+pub(crate) fn render(prometheus: &str) -> String {
+    let counters = prometheus
+        .lines()
+        .filter_map(|line| line.strip_prefix("# TYPE "))
+        .filter_map(|line| line.strip_suffix(" counter"))
+        .collect::<HashSet<_>>();
+    let family = |name: &str| name.strip_suffix("_total").unwrap_or(name).to_owned();
+
+    let mut output = String::with_capacity(prometheus.len() + 6);
+    for line in prometheus.lines().filter(|line| !line.is_empty()) {
+        let comment = ["# HELP ", "# TYPE "]
+            .into_iter()
+            .find(|prefix| line.starts_with(prefix));
+        match comment {
+            Some(prefix) => {
+                let (name, rest) = line[prefix.len()..].split_once(' ').unwrap_or((line, ""));
+                if counters.contains(name) {
+                    output.push_str(&format!("{prefix}{} {rest}", family(name)));
+                } else {
+                    output.push_str(line);
+                }
+            }
+            None if line.starts_with('#') => output.push_str(line),
+            None => {
+                let end = line.find(['{', ' ']).unwrap_or(line.len());
+                let name = &line[..end];
+                if counters.contains(name) && !name.ends_with("_total") {
+                    output.push_str(&format!("{name}_total{}", &line[end..]));
+                } else {
+                    output.push_str(line);
+                }
+            }
+        }
+        output.push('\n');
+    }
+    output.push_str("# EOF\n");
+
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_get_a_total_suffix() {
+        let prometheus = "\
+# HELP klipper_stats_mcu_bytes_read Bytes read
+# TYPE klipper_stats_mcu_bytes_read counter
+klipper_stats_mcu_bytes_read{name=\"mcu\"} 1024
+
+# TYPE mamalluca_export_errors_total counter
+mamalluca_export_errors_total{object_type=\"extruder\"} 2
+
+# TYPE klipper_up gauge
+klipper_up 1
+";
+
+        assert_eq!(
+            render(prometheus),
+            "\
+# HELP klipper_stats_mcu_bytes_read Bytes read
+# TYPE klipper_stats_mcu_bytes_read counter
+klipper_stats_mcu_bytes_read_total{name=\"mcu\"} 1024
+# TYPE mamalluca_export_errors counter
+mamalluca_export_errors_total{object_type=\"extruder\"} 2
+# TYPE klipper_up gauge
+klipper_up 1
+# EOF
+"
+        );
+    }
+
+    #[test]
+    fn openmetrics_is_negotiated() {
+        // Sent by Prometheus 2.x
+        assert!(accepted("application/openmetrics-text;version=1.0.0,application/openmetrics-text;version=0.0.1;q=0.75,text/plain;version=0.0.4;q=0.5,*/*;q=0.1"));
+        assert!(!accepted("text/plain;version=0.0.4"));
+        assert!(!accepted("application/openmetrics-text;q=0"));
+        assert!(!accepted("*/*"));
+    }
+}
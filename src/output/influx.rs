@@ -0,0 +1,406 @@
+use crate::output::Push;
+
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use dashmap::DashMap;
+use http_body_util::{BodyExt, Full};
+use hyper::header::{AUTHORIZATION, CONTENT_TYPE};
+use hyper::{Method, Request};
+use hyper_tls::HttpsConnector;
+use hyper_util::client::legacy::connect::HttpConnector;
+use hyper_util::client::legacy::Client;
+use hyper_util::rt::TokioExecutor;
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, HistogramFn, Key, KeyName, Metadata, Recorder,
+    SharedString, Unit,
+};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// Where to write metrics to with `--output influx`
+#[derive(Clone, Debug)]
+pub(crate) struct InfluxOptions {
+    /// Base URL of the InfluxDB server, e.g. `http://localhost:8086`
+    pub url: Url,
+    pub token: String,
+    pub org: String,
+    pub bucket: String,
+}
+
+/// Latest value of every metric, written to InfluxDB by [`InfluxWriter::push`]
+type Values = DashMap<Key, Arc<Series>>;
+
+/// Recorder keeping the latest value of every metric for [`InfluxWriter`]
+#[derive(Clone)]
+pub(crate) struct InfluxRecorder {
+    values: Arc<Values>,
+}
+
+impl InfluxRecorder {
+    pub fn new() -> Self {
+        Self {
+            values: Arc::new(DashMap::new()),
+        }
+    }
+
+    /// Forget every recorded value, e.g. once the global labels changed
+    pub fn clear(&self) {
+        self.values.clear();
+    }
+
+    /// Writer sending the values recorded by this recorder
+    pub fn writer(&self, options: InfluxOptions) -> anyhow::Result<InfluxWriter> {
+        Ok(InfluxWriter {
+            values: self.values.clone(),
+            client: Client::builder(TokioExecutor::new()).build(HttpsConnector::new()),
+            url: write_url(&options)?,
+            token: options.token,
+        })
+    }
+
+    fn register(&self, key: &Key, value: impl FnOnce() -> Value) -> Arc<Series> {
+        self.values
+            .entry(key.clone())
+            .or_insert_with(|| Arc::new(Series::new(value())))
+            .clone()
+    }
+}
+
+impl Recorder for InfluxRecorder {
+    fn describe_counter(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_gauge(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn describe_histogram(&self, _key: KeyName, _unit: Option<Unit>, _description: SharedString) {}
+
+    fn register_counter(&self, key: &Key, _metadata: &Metadata<'_>) -> Counter {
+        Counter::from_arc(self.register(key, || Value::Counter(AtomicU64::new(0))))
+    }
+
+    fn register_gauge(&self, key: &Key, _metadata: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(self.register(key, || Value::Gauge(AtomicU64::new(0f64.to_bits()))))
+    }
+
+    fn register_histogram(&self, key: &Key, _metadata: &Metadata<'_>) -> Histogram {
+        // Only the last sample is written, there is no aggregation between two pushes
+        Histogram::from_arc(self.register(key, || Value::Gauge(AtomicU64::new(0f64.to_bits()))))
+    }
+}
+
+/// The `/api/v2/write` endpoint below the base URL, which may include a path
+/// (e.g. behind a reverse proxy) with or without a trailing slash
+fn write_url(options: &InfluxOptions) -> anyhow::Result<Url> {
+    let mut url = options.url.clone();
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("Invalid InfluxDB URL {}", options.url))?
+        .pop_if_empty()
+        .extend(["api", "v2", "write"]);
+    url.query_pairs_mut()
+        .append_pair("org", &options.org)
+        .append_pair("bucket", &options.bucket)
+        .append_pair("precision", "s");
+
+    Ok(url)
+}
+
+/// A recorded value and whether it was updated since it was last written
+struct Series {
+    value: Value,
+    updated: AtomicBool,
+}
+
+impl Series {
+    fn new(value: Value) -> Self {
+        Self {
+            value,
+            updated: AtomicBool::new(false),
+        }
+    }
+
+    fn touch(&self) {
+        self.updated.store(true, Ordering::Relaxed);
+    }
+
+    /// The line protocol field value, `None` if the value was not updated
+    /// since the last call or can't be represented
+    fn take_field(&self) -> Option<String> {
+        self.updated
+            .swap(false, Ordering::Relaxed)
+            .then(|| self.value.field())
+            .flatten()
+    }
+}
+
+impl CounterFn for Series {
+    fn increment(&self, value: u64) {
+        CounterFn::increment(&self.value, value);
+        self.touch();
+    }
+
+    fn absolute(&self, value: u64) {
+        self.value.absolute(value);
+        self.touch();
+    }
+}
+
+impl GaugeFn for Series {
+    fn increment(&self, value: f64) {
+        GaugeFn::increment(&self.value, value);
+        self.touch();
+    }
+
+    fn decrement(&self, value: f64) {
+        self.value.decrement(value);
+        self.touch();
+    }
+
+    fn set(&self, value: f64) {
+        self.value.set(value);
+        self.touch();
+    }
+}
+
+impl HistogramFn for Series {
+    fn record(&self, value: f64) {
+        self.value.record(value);
+        self.touch();
+    }
+}
+
+enum Value {
+    Counter(AtomicU64),
+    /// `f64` bits
+    Gauge(AtomicU64),
+}
+
+impl Value {
+    fn update_gauge(&self, f: impl Fn(f64) -> f64) {
+        if let Value::Gauge(bits) = self {
+            let _ = bits.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |current| {
+                Some(f(f64::from_bits(current)).to_bits())
+            });
+        }
+    }
+
+    /// The line protocol field value, `None` if it can't be represented
+    fn field(&self) -> Option<String> {
+        match self {
+            Value::Counter(value) => Some(format!("{}i", value.load(Ordering::Relaxed))),
+            Value::Gauge(bits) => {
+                let value = f64::from_bits(bits.load(Ordering::Relaxed));
+                value.is_finite().then(|| value.to_string())
+            }
+        }
+    }
+}
+
+impl CounterFn for Value {
+    fn increment(&self, value: u64) {
+        if let Value::Counter(counter) = self {
+            counter.fetch_add(value, Ordering::Relaxed);
+        }
+    }
+
+    fn absolute(&self, value: u64) {
+        if let Value::Counter(counter) = self {
+            counter.store(value, Ordering::Relaxed);
+        }
+    }
+}
+
+impl GaugeFn for Value {
+    fn increment(&self, value: f64) {
+        self.update_gauge(|current| current + value)
+    }
+
+    fn decrement(&self, value: f64) {
+        self.update_gauge(|current| current - value)
+    }
+
+    fn set(&self, value: f64) {
+        self.update_gauge(|_| value)
+    }
+}
+
+impl HistogramFn for Value {
+    fn record(&self, value: f64) {
+        self.update_gauge(|_| value)
+    }
+}
+
+/// Escape `,`, `=` and ` ` (and `\` itself) in measurements and tags
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// One line of the InfluxDB line protocol: the metric name is the measurement
+/// and its labels are the tags of a `value` field
+fn line(key: &Key, field: &str, timestamp: u64) -> String {
+    let mut line = escape(key.name());
+    for label in key.labels() {
+        // Empty tag values are not allowed
+        if !label.value().is_empty() {
+            line.push_str(&format!(
+                ",{}={}",
+                escape(label.key()),
+                escape(label.value())
+            ));
+        }
+    }
+    format!("{line} value={field} {timestamp}")
+}
+
+/// Writes the values updated since the last push by an [`InfluxRecorder`] to InfluxDB.
+///
+/// Series that are no longer exported, e.g. of a removed sensor, are not written
+/// again, while unchanged values exported with every cycle still are.
+pub(crate) struct InfluxWriter {
+    values: Arc<Values>,
+    client: Client<HttpsConnector<HttpConnector>, Full<Bytes>>,
+    url: Url,
+    token: String,
+}
+
+impl InfluxWriter {
+    fn body(&self) -> String {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        self.values
+            .iter()
+            .filter_map(|entry| {
+                let field = entry.value().take_field()?;
+                Some(line(entry.key(), &field, timestamp) + "\n")
+            })
+            .collect()
+    }
+}
+
+#[async_trait::async_trait]
+impl Push for InfluxWriter {
+    async fn push(&self) -> anyhow::Result<()> {
+        let body = self.body();
+        if body.is_empty() {
+            return Ok(());
+        }
+
+        let request = Request::builder()
+            .method(Method::POST)
+            .uri(self.url.as_str())
+            .header(AUTHORIZATION, format!("Token {}", self.token))
+            .header(CONTENT_TYPE, "text/plain; charset=utf-8")
+            .body(Full::new(Bytes::from(body)))?;
+
+        let response = self
+            .client
+            .request(request)
+            .await
+            .context("Failed to write metrics to InfluxDB")?;
+        if !response.status().is_success() {
+            let status = response.status();
+            let body = response.into_body().collect().await?.to_bytes();
+            bail!(
+                "InfluxDB rejected metrics ({}): {}",
+                status,
+                String::from_utf8_lossy(&body)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metrics_become_line_protocol() {
+        let key = Key::from_parts(
+            "klipper.stats.temperature.current",
+            vec![
+                metrics::Label::new("name", "chamber left"),
+                metrics::Label::new("instance", "voron"),
+                metrics::Label::new("empty", ""),
+            ],
+        );
+
+        assert_eq!(
+            line(&key, "41.5", 1700000000),
+            "klipper.stats.temperature.current,name=chamber\\ left,instance=voron value=41.5 1700000000"
+        );
+    }
+
+    #[test]
+    fn only_representable_values_are_written() {
+        assert_eq!(
+            Value::Counter(AtomicU64::new(12)).field(),
+            Some("12i".into())
+        );
+        assert_eq!(
+            Value::Gauge(AtomicU64::new(0.25f64.to_bits())).field(),
+            Some("0.25".into())
+        );
+        assert_eq!(
+            Value::Gauge(AtomicU64::new(f64::NAN.to_bits())).field(),
+            None
+        );
+    }
+
+    fn options(url: &str) -> InfluxOptions {
+        InfluxOptions {
+            url: Url::parse(url).unwrap(),
+            token: "secret".into(),
+            org: "home".into(),
+            bucket: "printers".into(),
+        }
+    }
+
+    #[test]
+    fn write_url_keeps_the_base_path() {
+        for base in [
+            "http://influx:8086",
+            "http://proxy/influx",
+            "http://proxy/influx/",
+        ] {
+            let url = write_url(&options(base)).unwrap();
+            let expected = base.trim_end_matches('/').to_owned()
+                + "/api/v2/write?org=home&bucket=printers&precision=s";
+            assert_eq!(url.as_str(), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_updated_values_are_written() {
+        let recorder = InfluxRecorder::new();
+        let writer = recorder.writer(options("http://influx:8086")).unwrap();
+        let record = |value| {
+            metrics::with_local_recorder(&recorder, || {
+                metrics::gauge!("klipper.stats.extruder.temperature").set(value);
+            })
+        };
+
+        record(210.0);
+        assert!(writer
+            .body()
+            .starts_with("klipper.stats.extruder.temperature value=210 "));
+        assert_eq!(writer.body(), "");
+
+        // Setting an unchanged value is an update as well
+        record(210.0);
+        assert!(writer
+            .body()
+            .starts_with("klipper.stats.extruder.temperature value=210 "));
+    }
+}
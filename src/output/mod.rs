@@ -0,0 +1,22 @@
+mod influx;
+mod statsd;
+
+pub(crate) use influx::{InfluxOptions, InfluxRecorder};
+pub(crate) use statsd::StatsdRecorder;
+
+/// Metrics backends selectable with `--output`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub(crate) enum Output {
+    /// Serve metrics for Prometheus to scrape on `--prometheus-listen-address`
+    Prometheus,
+    /// Push metrics to `--statsd-address`, labels are sent as DogStatsD tags
+    Statsd,
+    /// Write metrics to the InfluxDB v2 bucket `--influx-bucket` on every export
+    Influx,
+}
+
+/// Backend sending the collected metrics to a server after every export cycle
+#[async_trait::async_trait]
+pub(crate) trait Push: Send + Sync {
+    async fn push(&self) -> anyhow::Result<()>;
+}
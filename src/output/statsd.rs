@@ -0,0 +1,181 @@
+use anyhow::Context;
+use metrics::{
+    Counter, CounterFn, Gauge, GaugeFn, Histogram, Key, KeyName, Metadata, Recorder, SharedString,
+    Unit,
+};
+use metrics_exporter_statsd::StatsdBuilder;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Recorder pushing every update to a StatsD server.
+///
+/// Labels are sent as DogStatsD tags (`name:value|g|#label:value`), which
+/// servers without tag support ignore or reject depending on the implementation.
+pub(crate) struct StatsdRecorder<R = metrics_exporter_statsd::StatsdRecorder> {
+    inner: R,
+}
+
+impl StatsdRecorder {
+    /// Create a recorder sending to `address` (`host:port`)
+    pub fn new(address: &str) -> anyhow::Result<Self> {
+        let (host, port) = address
+            .rsplit_once(':')
+            .and_then(|(host, port)| {
+                let host = host.trim_start_matches('[').trim_end_matches(']');
+                Some((host, port.parse::<u16>().ok()?))
+            })
+            .with_context(|| format!("Invalid StatsD address {address}, expected HOST:PORT"))?;
+
+        // Bind the socket for the address family of IPv6 literals like `[::1]:8125`
+        let local = if host.contains(':') { "::" } else { "0.0.0.0" };
+        let inner = StatsdBuilder::from(host, port)
+            .with_client_udp_host(local)
+            // Send every update right away instead of once 256 bytes are buffered
+            .with_buffer_size(0)
+            .build(None)
+            .with_context(|| format!("Failed to create StatsD client for {address}"))?;
+
+        Ok(Self { inner })
+    }
+}
+
+impl<R: Recorder> Recorder for StatsdRecorder<R> {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_counter(key, unit, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_gauge(key, unit, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_histogram(key, unit, description)
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        Counter::from_arc(Arc::new(IncrementalCounter {
+            inner: self.inner.register_counter(key, metadata),
+            last: AtomicU64::new(0),
+        }))
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        Gauge::from_arc(Arc::new(FiniteGauge {
+            inner: self.inner.register_gauge(key, metadata),
+        }))
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        self.inner.register_histogram(key, metadata)
+    }
+}
+
+/// Counter sending absolute values as increments, StatsD counters only receive the increments
+struct IncrementalCounter {
+    inner: Counter,
+    /// Last absolute value
+    last: AtomicU64,
+}
+
+impl CounterFn for IncrementalCounter {
+    fn increment(&self, value: u64) {
+        self.last.fetch_add(value, Ordering::Relaxed);
+        self.inner.increment(value);
+    }
+
+    fn absolute(&self, value: u64) {
+        let last = self.last.swap(value, Ordering::Relaxed);
+        // A smaller value means the source restarted counting, e.g. after a Klipper restart
+        let increment = if value >= last { value - last } else { value };
+        if increment > 0 {
+            self.inner.increment(increment);
+        }
+    }
+}
+
+/// Gauge dropping NaN and infinite values, StatsD has no representation for them
+struct FiniteGauge {
+    inner: Gauge,
+}
+
+impl GaugeFn for FiniteGauge {
+    fn increment(&self, value: f64) {
+        self.inner.increment(value)
+    }
+
+    fn decrement(&self, value: f64) {
+        self.inner.decrement(value)
+    }
+
+    fn set(&self, value: f64) {
+        if value.is_finite() {
+            self.inner.set(value)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+    #[test]
+    fn absolute_counters_send_increments() {
+        let debugging = DebuggingRecorder::new();
+        let snapshotter = debugging.snapshotter();
+        let recorder = StatsdRecorder { inner: debugging };
+        let counter = recorder.register_counter(
+            &Key::from_name("klipper.stats.mcu.bytes_read"),
+            &Metadata::new("", metrics::Level::INFO, None),
+        );
+
+        counter.absolute(100);
+        counter.absolute(100);
+        counter.absolute(150);
+        // Klipper restarted
+        counter.absolute(20);
+
+        let values = snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .map(|(_, _, _, value)| value)
+            .collect::<Vec<_>>();
+        assert_eq!(values, vec![DebugValue::Counter(170)]);
+    }
+
+    #[test]
+    fn labels_are_sent_as_tags() {
+        let receiver = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        receiver
+            .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+            .unwrap();
+        let recorder = StatsdRecorder::new(&receiver.local_addr().unwrap().to_string()).unwrap();
+        let gauge = recorder.register_gauge(
+            &Key::from_parts(
+                "klipper.stats.extruder.temperature",
+                vec![
+                    metrics::Label::new("name", "extruder"),
+                    metrics::Label::new("instance", "voron"),
+                ],
+            ),
+            &Metadata::new("", metrics::Level::INFO, None),
+        );
+
+        gauge.set(f64::NAN);
+        gauge.set(209.5);
+
+        let mut buffer = [0; 128];
+        let length = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buffer[..length]),
+            "klipper.stats.extruder.temperature:209.5|g|#name:extruder,instance:voron"
+        );
+    }
+
+    #[test]
+    fn rejects_addresses_without_port() {
+        assert!(StatsdRecorder::new("localhost").is_err());
+        assert!(StatsdRecorder::new("localhost:statsd").is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use metrics::{
+    Counter, Gauge, Histogram, Key, KeyName, Label, Metadata, Recorder, SharedString, Unit,
+};
+use metrics_util::layers::Layer;
+use std::sync::{Arc, RwLock};
+
+/// Labels attached to every metric passing through the installed recorder.
+///
+/// The set can be updated at runtime, e.g. once the printer hostname is known.
+/// Metrics emitted afterwards carry the new values, series registered with the
+/// previous values are left to the output, see [`GlobalLabels::on_change`].
+#[derive(Clone, Default)]
+pub(crate) struct GlobalLabels {
+    labels: Arc<RwLock<Vec<Label>>>,
+    on_change: Arc<RwLock<Option<ChangeCallback>>>,
+}
+
+type ChangeCallback = Box<dyn Fn() + Send + Sync>;
+
+impl std::fmt::Debug for GlobalLabels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("GlobalLabels").field(&self.all()).finish()
+    }
+}
+
+impl GlobalLabels {
+    /// Set `key` to `value`, replacing any previous value for the same key.
+    ///
+    /// Returns whether a previous value was replaced by a different one.
+    pub fn insert(&self, key: impl Into<SharedString>, value: String) -> bool {
+        let key = key.into();
+        let changed = {
+            let mut labels = self.labels.write().unwrap_or_else(|e| e.into_inner());
+            let previous = labels.iter().position(|label| label.key() == key.as_ref());
+            let changed = previous.is_some_and(|i| labels[i].value() != value);
+            labels.retain(|label| label.key() != key.as_ref());
+            labels.push(Label::new(key, value));
+            changed
+        };
+
+        if changed {
+            let on_change = self.on_change.read().unwrap_or_else(|e| e.into_inner());
+            if let Some(on_change) = on_change.as_ref() {
+                on_change();
+            }
+        }
+        changed
+    }
+
+    /// Current value of the label `key`
+    pub fn get(&self, key: &str) -> Option<String> {
+        self.labels
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .iter()
+            .find(|label| label.key() == key)
+            .map(|label| label.value().to_owned())
+    }
+
+    /// Call `f` whenever the value of a label changes, e.g. to drop the series
+    /// recorded with the previous value
+    pub fn on_change(&self, f: impl Fn() + Send + Sync + 'static) {
+        *self.on_change.write().unwrap_or_else(|e| e.into_inner()) = Some(Box::new(f));
+    }
+
+    fn all(&self) -> Vec<Label> {
+        self.labels
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+/// A recorder that can be replaced at runtime, dropping every series recorded so far
+pub(crate) struct ReplaceableRecorder<R>(Arc<RwLock<Arc<R>>>);
+
+impl<R> Clone for ReplaceableRecorder<R> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<R> ReplaceableRecorder<R> {
+    pub fn new(recorder: R) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(recorder))))
+    }
+
+    pub fn current(&self) -> Arc<R> {
+        self.0.read().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    pub fn replace(&self, recorder: R) {
+        *self.0.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(recorder);
+    }
+}
+
+impl<R: Recorder> Recorder for ReplaceableRecorder<R> {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.current().describe_counter(key, unit, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.current().describe_gauge(key, unit, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.current().describe_histogram(key, unit, description)
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        self.current().register_counter(key, metadata)
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        self.current().register_gauge(key, metadata)
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        self.current().register_histogram(key, metadata)
+    }
+}
+
+/// Labels that are set by the exporter itself and can't be overridden with `--label`
+const RESERVED_LABELS: &[&str] = &["instance"];
+
+/// Parse a `--label key=value` argument
+pub(crate) fn parse_label(label: &str) -> Result<(String, String), String> {
+    let (key, value) = label
+        .split_once('=')
+        .ok_or_else(|| format!("expected `key=value`, got `{label}`"))?;
+
+    let mut chars = key.chars();
+    let valid_start = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_');
+    if !valid_start || !chars.all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Err(format!(
+            "invalid label name `{key}`, must start with a letter or `_` and only contain letters, digits and `_`"
+        ));
+    }
+    if key.starts_with("__") || RESERVED_LABELS.contains(&key) {
+        return Err(format!("label name `{key}` is reserved"));
+    }
+
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// Recorder layer adding [`GlobalLabels`] to every registered metric
+pub(crate) struct GlobalLabelsLayer(GlobalLabels);
+
+impl GlobalLabelsLayer {
+    pub fn new(labels: GlobalLabels) -> Self {
+        Self(labels)
+    }
+}
+
+impl<R> Layer<R> for GlobalLabelsLayer {
+    type Output = WithGlobalLabels<R>;
+
+    fn layer(&self, inner: R) -> Self::Output {
+        WithGlobalLabels {
+            labels: self.0.clone(),
+            inner,
+        }
+    }
+}
+
+pub(crate) struct WithGlobalLabels<R> {
+    labels: GlobalLabels,
+    inner: R,
+}
+
+impl<R> WithGlobalLabels<R> {
+    /// Add the global labels to `key`, labels of the series itself (e.g. `name`
+    /// of a heater) take precedence over a global label with the same key
+    fn labeled(&self, key: &Key) -> Key {
+        let mut labels = self.labels.all();
+        labels.retain(|global| !key.labels().any(|label| label.key() == global.key()));
+        key.with_extra_labels(labels)
+    }
+}
+
+impl<R: Recorder> Recorder for WithGlobalLabels<R> {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_counter(key, unit, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_gauge(key, unit, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner.describe_histogram(key, unit, description)
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        self.inner.register_counter(&self.labeled(key), metadata)
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        self.inner.register_gauge(&self.labeled(key), metadata)
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        self.inner.register_histogram(&self.labeled(key), metadata)
+    }
+}
+
+/// Leading name segments removed by `--drop-metric-prefix`
+const DROPPABLE_PREFIXES: &[&str] = &["klipper.", "moonraker."];
+
+/// Recorder layer rewriting metric names before they reach the exporter
+pub(crate) struct MetricNamesLayer {
+    drop_prefix: bool,
+    prefix: Option<String>,
+}
+
+impl MetricNamesLayer {
+    pub fn new(drop_prefix: bool, prefix: Option<String>) -> Self {
+        Self {
+            drop_prefix,
+            prefix: prefix.filter(|prefix| !prefix.is_empty()),
+        }
+    }
+}
+
+impl<R> Layer<R> for MetricNamesLayer {
+    type Output = MetricNames<R>;
+
+    fn layer(&self, inner: R) -> Self::Output {
+        MetricNames {
+            drop_prefix: self.drop_prefix,
+            prefix: self.prefix.clone(),
+            inner,
+        }
+    }
+}
+
+pub(crate) struct MetricNames<R> {
+    drop_prefix: bool,
+    prefix: Option<String>,
+    inner: R,
+}
+
+impl<R> MetricNames<R> {
+    fn rename(&self, name: &str) -> String {
+        let name = if self.drop_prefix {
+            DROPPABLE_PREFIXES
+                .iter()
+                .filter_map(|prefix| name.strip_prefix(prefix))
+                // Keep single segment names like `klipper.up` intact, a bare `up`
+                // would clash with the metric Prometheus generates for every target
+                .find(|stripped| stripped.contains('.'))
+                .unwrap_or(name)
+        } else {
+            name
+        };
+
+        match &self.prefix {
+            Some(prefix) => format!("{prefix}.{name}"),
+            None => name.to_owned(),
+        }
+    }
+
+    fn rename_key(&self, key: &Key) -> Key {
+        Key::from_parts(self.rename(key.name()), key.labels())
+    }
+
+    fn rename_key_name(&self, key: KeyName) -> KeyName {
+        KeyName::from(self.rename(key.as_str()))
+    }
+}
+
+/// Validate a `--metric-prefix`, it has to be a valid start of a Prometheus metric name
+pub(crate) fn parse_metric_prefix(prefix: &str) -> Result<String, String> {
+    let mut chars = prefix.chars();
+    let valid_start = chars
+        .next()
+        .is_none_or(|c| c.is_ascii_alphabetic() || c == '_');
+    let valid_rest = chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.'));
+
+    if valid_start && valid_rest {
+        Ok(prefix.to_owned())
+    } else {
+        Err("must start with a letter or `_` and only contain letters, digits, `_` and `.`".into())
+    }
+}
+
+impl<R: Recorder> Recorder for MetricNames<R> {
+    fn describe_counter(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner
+            .describe_counter(self.rename_key_name(key), unit, description)
+    }
+
+    fn describe_gauge(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner
+            .describe_gauge(self.rename_key_name(key), unit, description)
+    }
+
+    fn describe_histogram(&self, key: KeyName, unit: Option<Unit>, description: SharedString) {
+        self.inner
+            .describe_histogram(self.rename_key_name(key), unit, description)
+    }
+
+    fn register_counter(&self, key: &Key, metadata: &Metadata<'_>) -> Counter {
+        self.inner.register_counter(&self.rename_key(key), metadata)
+    }
+
+    fn register_gauge(&self, key: &Key, metadata: &Metadata<'_>) -> Gauge {
+        self.inner.register_gauge(&self.rename_key(key), metadata)
+    }
+
+    fn register_histogram(&self, key: &Key, metadata: &Metadata<'_>) -> Histogram {
+        self.inner
+            .register_histogram(&self.rename_key(key), metadata)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+
+    #[test]
+    fn changed_labels_replace_the_recorder() {
+        let labels = GlobalLabels::default();
+        let recorder = ReplaceableRecorder::new(PrometheusBuilder::new().build_recorder());
+        labels.on_change({
+            let recorder = recorder.clone();
+            move || recorder.replace(PrometheusBuilder::new().build_recorder())
+        });
+        let layered = GlobalLabelsLayer::new(labels.clone()).layer(recorder.clone());
+
+        assert!(!labels.insert("instance", "10.0.0.5".to_owned()));
+        metrics::with_local_recorder(&layered, || metrics::gauge!("klipper.up").set(1.0));
+        // Setting the same value again keeps the series
+        assert!(!labels.insert("instance", "10.0.0.5".to_owned()));
+        assert!(recorder.current().handle().render().contains("10.0.0.5"));
+
+        assert!(labels.insert("instance", "voron".to_owned()));
+        metrics::with_local_recorder(&layered, || metrics::gauge!("klipper.up").set(1.0));
+        let output = recorder.current().handle().render();
+        assert!(
+            output.contains("klipper_up{instance=\"voron\"} 1"),
+            "{output}"
+        );
+        assert!(!output.contains("10.0.0.5"), "{output}");
+    }
+
+    #[test]
+    fn series_labels_take_precedence_over_global_labels() {
+        let labels = GlobalLabels::default();
+        labels.insert("name", "voron".to_owned());
+        labels.insert("site", "garage".to_owned());
+        let recorder = PrometheusBuilder::new().build_recorder();
+        let handle = recorder.handle();
+        let recorder = GlobalLabelsLayer::new(labels).layer(recorder);
+
+        metrics::with_local_recorder(&recorder, || {
+            metrics::gauge!("klipper.stats.extruder.temperature", "name" => "extruder").set(210.0);
+            metrics::gauge!("klipper.up").set(1.0);
+        });
+
+        let output = handle.render();
+        assert!(
+            output.contains(
+                "klipper_stats_extruder_temperature{name=\"extruder\",site=\"garage\"} 210"
+            ),
+            "{output}"
+        );
+        assert!(
+            output.contains("klipper_up{name=\"voron\",site=\"garage\"} 1"),
+            "{output}"
+        );
+    }
+}
@@ -1,40 +1,78 @@
-use crate::types::MetricsExporter;
-use metrics::{counter, describe_counter, gauge, Unit};
+use crate::types::{CounterReading, ExportContext, MetricsExporter};
+use dashmap::DashMap;
+use metrics::{counter, describe_counter, describe_gauge, gauge, histogram, Unit};
 use serde::{Deserialize, Serialize};
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+/// State reported by `webhooks`.
+///
+/// Klipper reports the state in lowercase, capitalized spellings are accepted as
+/// well and states added by newer Klipper versions are exported as unknown.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "lowercase")]
 pub(crate) enum KlippyState {
+    #[serde(alias = "Ready")]
     Ready,
+    #[serde(alias = "Error")]
     Error,
+    #[serde(alias = "Shutdown")]
     Shutdown,
+    #[serde(alias = "Startup")]
     Startup,
+    #[serde(other)]
+    Unknown,
 }
 
+impl KlippyState {
+    /// The state as reported by Klipper
+    fn name(&self) -> &'static str {
+        match self {
+            KlippyState::Startup => "startup",
+            KlippyState::Ready => "ready",
+            KlippyState::Shutdown => "shutdown",
+            KlippyState::Error => "error",
+            KlippyState::Unknown => "unknown",
+        }
+    }
+
+    /// Numeric value exported for the state
+    fn value(&self) -> f64 {
+        match self {
+            KlippyState::Startup => 0.0,
+            KlippyState::Ready => 1.0,
+            KlippyState::Shutdown => 2.0,
+            KlippyState::Error => 3.0,
+            KlippyState::Unknown => -1.0,
+        }
+    }
+}
+
+/// Statistics of an MCU connection (`last_stats`).
+///
+/// Which fields are reported depends on the Klipper version and the transport,
+/// so everything is optional and only exported when present.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct McuStats {
     #[serde(default)]
     adj: u64,
-    #[serde(default)]
-    bytes_invalid: u64,
-    #[serde(default)]
-    bytes_read: u64,
-    #[serde(default)]
-    bytes_retransmit: u64,
-    bytes_write: u64,
-    freq: u64,
-    mcu_awake: f64,
-    mcu_task_avg: f64,
-    mcu_task_stddev: f64,
-    ready_bytes: u64,
-    upcoming_bytes: u64,
-    send_seq: u64,
-    receive_seq: u64,
-    retransmit_seq: u64,
-    srtt: f64,
-    rto: f64,
-    rttvar: f64,
+    bytes_invalid: Option<u64>,
+    bytes_read: Option<u64>,
+    bytes_retransmit: Option<u64>,
+    bytes_write: Option<u64>,
+    freq: Option<u64>,
+    mcu_awake: Option<f64>,
+    mcu_task_avg: Option<f64>,
+    mcu_task_stddev: Option<f64>,
+    ready_bytes: Option<u64>,
+    upcoming_bytes: Option<u64>,
+    send_seq: Option<u64>,
+    receive_seq: Option<u64>,
+    retransmit_seq: Option<u64>,
+    srtt: Option<f64>,
+    rto: Option<f64>,
+    rttvar: Option<f64>,
 }
 
 impl MetricsExporter for McuStats {
@@ -43,36 +81,102 @@ impl MetricsExporter for McuStats {
         describe_counter!("klipper.stats.mcu.bytes_read", Unit::Bytes, "");
         describe_counter!("klipper.stats.mcu.bytes_write", Unit::Bytes, "");
         describe_counter!("klipper.stats.mcu.bytes_retransmit", Unit::Bytes, "");
-        describe_counter!("klipper.stats.mcu.ready_bytes", Unit::Bytes, "");
-        describe_counter!("klipper.stats.mcu.upcomping_bytes", Unit::Bytes, "");
+        describe_gauge!("klipper.stats.mcu.ready_bytes", Unit::Bytes, "");
+        describe_gauge!("klipper.stats.mcu.upcoming_bytes", Unit::Bytes, "");
     }
 
-    fn export(&self, name: Option<&String>) {
-        let mut labels = Vec::new();
-        if let Some(name) = name {
-            labels.push(("name", name.to_owned()));
-        }
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
+        let gauges = [
+            ("klipper.stats.mcu.freq", self.freq.map(|v| v as f64)),
+            ("klipper.stats.mcu.mcu_awake", self.mcu_awake),
+            ("klipper.stats.mcu.mcu_task_avg", self.mcu_task_avg),
+            ("klipper.stats.mcu.mcu_task_stddev", self.mcu_task_stddev),
+            (
+                "klipper.stats.mcu.ready_bytes",
+                self.ready_bytes.map(|v| v as f64),
+            ),
+            (
+                "klipper.stats.mcu.upcoming_bytes",
+                self.upcoming_bytes.map(|v| v as f64),
+            ),
+            ("klipper.stats.mcu.rto", self.rto),
+            ("klipper.stats.mcu.rttvar", self.rttvar),
+            ("klipper.stats.mcu.srtt", self.srtt),
+        ];
 
         gauge!("klipper.stats.mcu.adj", &labels).set(self.adj as f64);
-        gauge!("klipper.stats.mcu.freq", &labels).set(self.freq as f64);
-        gauge!("klipper.stats.mcu.mcu_awake", &labels).set(self.mcu_awake);
-        gauge!("klipper.stats.mcu.mcu_task_avg", &labels).set(self.mcu_task_avg);
-        gauge!("klipper.stats.mcu.mcu_task_stddev", &labels).set(self.mcu_task_stddev);
-        gauge!("klipper.stats.mcu.ready_bytes", &labels).set(self.ready_bytes as f64);
-        gauge!("klipper.stats.mcu.upcoming_bytes", &labels).set(self.upcoming_bytes as f64);
+        for (name, value) in gauges {
+            if let Some(value) = ctx.nullable(value) {
+                gauge!(name, &labels).set(value);
+            }
+        }
+    }
+
+    fn counters(&self, ctx: &ExportContext) -> Vec<CounterReading> {
+        let counters = [
+            ("klipper.stats.mcu.bytes_read", self.bytes_read),
+            ("klipper.stats.mcu.bytes_write", self.bytes_write),
+            ("klipper.stats.mcu.bytes_invalid", self.bytes_invalid),
+            ("klipper.stats.mcu.bytes_retransmit", self.bytes_retransmit),
+            ("klipper.stats.mcu.receive_seq", self.receive_seq),
+            ("klipper.stats.mcu.send_seq", self.send_seq),
+            ("klipper.stats.mcu.retransmit_seq", self.retransmit_seq),
+        ];
+        counters
+            .into_iter()
+            .filter_map(|(name, value)| Some((name, ctx.labels(), value?)))
+            .collect()
+    }
+}
 
-        counter!("klipper.stats.mcu.bytes_read", &labels).absolute(self.bytes_read);
-        counter!("klipper.stats.mcu.bytes_write", &labels).absolute(self.bytes_write);
-        counter!("klipper.stats.mcu.bytes_invalid", &labels).absolute(self.bytes_invalid);
-        counter!("klipper.stats.mcu.bytes_retransmit", &labels).absolute(self.bytes_retransmit);
+impl McuStats {
+    /// Record the round trip times as histograms, on every status update rather
+    /// than every export so spikes between two scrapes are kept
+    pub fn record_rtt(&self, name: &str) {
+        let labels = [("name", name.to_owned())];
+        let histograms = [
+            ("klipper.stats.mcu.rto_seconds", self.rto),
+            ("klipper.stats.mcu.rttvar_seconds", self.rttvar),
+            ("klipper.stats.mcu.srtt_seconds", self.srtt),
+        ];
+        for (name, value) in histograms {
+            if let Some(value) = value {
+                histogram!(name, &labels).record(value);
+            }
+        }
+    }
+}
+
+/// Identifier of the primary MCU all other MCU clocks are synchronized to
+const PRIMARY_MCU: &str = "mcu";
+
+impl McuStats {
+    /// Ratio of the adjusted to the nominal clock frequency.
+    ///
+    /// Only secondary MCUs report `adj`, the primary MCU is the reference clock.
+    fn clock_ratio(&self) -> f64 {
+        match self.freq {
+            Some(freq) if self.adj != 0 && freq != 0 => self.adj as f64 / freq as f64,
+            _ => 1.0,
+        }
+    }
+}
 
-        counter!("klipper.stats.mcu.receive_seq", &labels).absolute(self.receive_seq);
-        counter!("klipper.stats.mcu.send_seq", &labels).absolute(self.send_seq);
-        counter!("klipper.stats.mcu.retransmit_seq", &labels).absolute(self.retransmit_seq);
+/// Export the clock drift of every secondary MCU relative to the primary `mcu`.
+///
+/// This correlates MCU objects with each other and therefore needs the stats
+/// of all MCUs from the same export cycle. Nothing is exported while the
+/// primary MCU has not reported its stats.
+fn export_mcu_clock_skew(mcus: &HashMap<String, McuStats>) {
+    let Some(primary) = mcus.get(PRIMARY_MCU) else {
+        return;
+    };
 
-        gauge!("klipper.stats.mcu.rto", &labels).set(self.rto);
-        gauge!("klipper.stats.mcu.rttvar", &labels).set(self.rttvar);
-        gauge!("klipper.stats.mcu.srtt", &labels).set(self.srtt);
+    for (name, stats) in mcus.iter().filter(|(name, _)| *name != PRIMARY_MCU) {
+        let labels = vec![("name", name.to_owned())];
+        let skew = (stats.clock_ratio() - primary.clock_ratio()) * 1_000_000.0;
+        gauge!("klipper.stats.mcu.clock_skew_ppm", &labels).set(skew);
     }
 }
 
@@ -84,21 +188,126 @@ pub(crate) struct WebhooksStats {
     state_message: String,
 }
 
-impl MetricsExporter for WebhooksStats {}
+impl MetricsExporter for WebhooksStats {
+    fn export(&self, _ctx: &ExportContext) {
+        gauge!("klipper.webhooks.state").set(self.state.value());
+    }
+}
 
-#[derive(Clone, Debug, Deserialize, Serialize)]
-pub(crate) struct HeaterInformation {
-    available_heaters: HashSet<String>,
-    available_sensors: HashSet<String>,
-    available_monitors: HashSet<String>,
+/// Maximum length of free-form messages exported as a label
+const MESSAGE_LABEL_LENGTH: usize = 120;
+
+/// The first line of `message` with collapsed whitespace, truncated for use as a label value
+fn message_label(message: &str) -> String {
+    message
+        .lines()
+        .next()
+        .unwrap_or_default()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .chars()
+        .take(MESSAGE_LABEL_LENGTH)
+        .collect()
+}
+
+/// A gauge describing the current value through its labels, e.g. a message.
+///
+/// Only one label set is current at a time, the series of the previously set
+/// labels is zeroed once they change.
+#[derive(Debug, Default)]
+pub(crate) struct InfoGauge {
+    /// The series set last
+    current: Mutex<Option<InfoSeries>>,
+}
+
+#[derive(Debug)]
+struct InfoSeries {
+    name: &'static str,
+    labels: Vec<(&'static str, String)>,
+    value: f64,
+}
+
+impl InfoGauge {
+    pub fn set(&self, name: &'static str, labels: Vec<(&'static str, String)>, value: f64) {
+        let mut current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some(previous) = current.replace(InfoSeries {
+            name,
+            labels: labels.clone(),
+            value,
+        }) {
+            if previous.labels != labels {
+                gauge!(previous.name, &previous.labels).set(0.0);
+            }
+        }
+
+        gauge!(name, &labels).set(value);
+    }
+
+    /// Export the series set last again
+    pub fn export(&self) {
+        let current = self.current.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(series) = current.as_ref() {
+            gauge!(series.name, &series.labels).set(series.value);
+        }
+    }
+}
+
+impl WebhooksStats {
+    /// The first line of the state message if Klipper halted due to a config error.
+    ///
+    /// Config errors end with instructions to issue a `RESTART`, while MCU
+    /// errors ask for a `FIRMWARE_RESTART` instead.
+    fn config_error(&self) -> Option<String> {
+        let message = &self.state_message;
+        let is_config_error = matches!(self.state, KlippyState::Error)
+            && message.contains("\"RESTART\"")
+            && !message.contains("\"FIRMWARE_RESTART\"");
+
+        is_config_error.then(|| message_label(message))
+    }
+}
+
+/// Exports `klipper.config_error` and `klipper.webhooks.info`, labelled with the state message
+#[derive(Debug, Default)]
+pub(crate) struct WebhooksTracker {
+    config_error: InfoGauge,
+    info: InfoGauge,
+}
+
+impl WebhooksTracker {
+    pub fn observe(&self, stats: &WebhooksStats) {
+        let error = stats.config_error();
+        self.config_error.set(
+            "klipper.config_error",
+            vec![("message", error.clone().unwrap_or_default())],
+            error.is_some() as u8 as f64,
+        );
+
+        self.info.set(
+            "klipper.webhooks.info",
+            vec![
+                ("state", stats.state.name().to_owned()),
+                ("message", message_label(&stats.state_message)),
+            ],
+            1.0,
+        );
+    }
+
+    pub fn export(&self) {
+        self.config_error.export();
+        self.info.export();
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct ExtruderStats {
     can_extrude: bool,
     power: f64,
-    pressure_advance: f64,
-    smooth_time: f64,
+    /// Only reported for extruders with a stepper
+    pressure_advance: Option<f64>,
+    smooth_time: Option<f64>,
     target: f64,
     temperature: f64,
     time_offset: Option<f64>,
@@ -106,25 +315,34 @@ pub(crate) struct ExtruderStats {
 
 impl MetricsExporter for ExtruderStats {
     fn describe(&self) {
-        describe_counter!("klipper.stats.extruder.smooth_time", Unit::Seconds, "");
+        describe_gauge!("klipper.stats.extruder.smooth_time", Unit::Seconds, "");
+        describe_gauge!("klipper.stats.extruder.smooth_tmime", Unit::Seconds, "");
     }
 
-    fn export(&self, name: Option<&String>) {
-        let mut labels = Vec::new();
-        if let Some(name) = name {
-            labels.push(("name", name.to_owned()));
-        }
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
 
         gauge!("klipper.stats.extruder.can_extrude", &labels).set(self.can_extrude as u8 as f64);
         gauge!("klipper.stats.extruder.power", &labels).set(self.power);
-        gauge!("klipper.stats.extruder.pressure_advance", &labels).set(self.pressure_advance);
-        gauge!("klipper.stats.extruder.smooth_tmime", &labels).set(self.smooth_time);
+        if let Some(pressure_advance) = ctx.nullable(self.pressure_advance) {
+            gauge!("klipper.stats.extruder.pressure_advance", &labels).set(pressure_advance);
+        }
+        if let Some(smooth_time) = ctx.nullable(self.smooth_time) {
+            gauge!("klipper.stats.extruder.smooth_time", &labels).set(smooth_time);
+            // Misspelled name exported before 0.1.2, kept for one release so dashboards can migrate
+            gauge!("klipper.stats.extruder.smooth_tmime", &labels).set(smooth_time);
+        }
         gauge!("klipper.stats.extruder.target", &labels).set(self.target);
         gauge!("klipper.stats.extruder.temperature", &labels).set(self.temperature);
+        if let Some(error) = self.temp_error() {
+            gauge!("klipper.stats.extruder.temp_error", &labels).set(error);
+        }
 
-        if let Some(time_offset) = self.time_offset {
+        if let Some(time_offset) = ctx.nullable(self.time_offset) {
             gauge!("klipper.stats.extruder.time_offset", &labels).set(time_offset);
         }
+
+        export_heater(ctx, self.power, self);
     }
 }
 
@@ -135,36 +353,185 @@ pub(crate) struct HeaterBedStats {
     temperature: f64,
 }
 
-impl MetricsExporter for HeaterBedStats {
-    fn export(&self, name: Option<&String>) {
-        let mut labels = Vec::new();
-        if let Some(name) = name {
-            labels.push(("name", name.to_owned()));
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct HeaterGenericStats {
+    power: f64,
+    target: f64,
+    temperature: f64,
+}
+
+impl MetricsExporter for HeaterGenericStats {
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
+
+        gauge!("klipper.stats.heater_generic.power", &labels).set(self.power);
+        gauge!("klipper.stats.heater_generic.target", &labels).set(self.target);
+        gauge!("klipper.stats.heater_generic.temperature", &labels).set(self.temperature);
+        if let Some(error) = self.temp_error() {
+            gauge!("klipper.stats.heater_generic.temp_error", &labels).set(error);
+        }
+
+        export_heater(ctx, self.power, self);
+    }
+}
+
+/// Readings shared by all heater objects
+pub(crate) trait Heater {
+    fn target(&self) -> f64;
+    fn temperature(&self) -> f64;
+
+    /// Whether the temperature is within `deadband` degrees of the target
+    fn at_target(&self, deadband: f64) -> bool {
+        (self.temperature() - self.target()).abs() <= deadband
+    }
+
+    /// Degrees the temperature is below the target, `None` while the heater is off
+    fn temp_error(&self) -> Option<f64> {
+        (self.target() > 0.0).then(|| self.target() - self.temperature())
+    }
+}
+
+impl Heater for ExtruderStats {
+    fn target(&self) -> f64 {
+        self.target
+    }
+
+    fn temperature(&self) -> f64 {
+        self.temperature
+    }
+}
+
+impl Heater for HeaterBedStats {
+    fn target(&self) -> f64 {
+        self.target
+    }
+
+    fn temperature(&self) -> f64 {
+        self.temperature
+    }
+}
+
+impl Heater for HeaterGenericStats {
+    fn target(&self) -> f64 {
+        self.target
+    }
+
+    fn temperature(&self) -> f64 {
+        self.temperature
+    }
+}
+
+/// Export the readings of any heater object under common names, labelled with the heater
+fn export_heater(ctx: &ExportContext, power: f64, heater: &impl Heater) {
+    // Series of different heaters can't be told apart without their name
+    let Some(name) = ctx.name else {
+        return;
+    };
+    let labels = vec![("heater", name.to_owned())];
+
+    gauge!("klipper.stats.heater.power", &labels).set(power);
+    gauge!("klipper.stats.heater.target", &labels).set(heater.target());
+    gauge!("klipper.stats.heater.temperature", &labels).set(heater.temperature());
+    if let Some(error) = heater.temp_error() {
+        gauge!("klipper.stats.heater.temp_error", &labels).set(error);
+    }
+}
+
+/// How long after a target increase the temperature is watched for overshoot
+const OVERSHOOT_WINDOW: Duration = Duration::from_secs(300);
+
+#[derive(Debug)]
+struct OvershootState {
+    target: f64,
+    /// Start of the current heat-up, `None` after a cooldown or once the window passed
+    since: Option<Instant>,
+    peak: f64,
+}
+
+/// Tracks the peak overshoot of each heater after its target was raised
+#[derive(Debug, Default)]
+pub(crate) struct HeaterOvershootTracker {
+    heaters: DashMap<String, OvershootState>,
+}
+
+impl HeaterOvershootTracker {
+    pub fn observe(&self, name: &str, heater: &impl Heater) {
+        let (target, temperature) = (heater.target(), heater.temperature());
+        let now = Instant::now();
+
+        let mut state = self
+            .heaters
+            .entry(name.to_owned())
+            // The time of the last target change is unknown, so don't start tracking
+            .or_insert(OvershootState {
+                target,
+                since: None,
+                peak: 0.0,
+            });
+
+        if target != state.target {
+            state.since = (target > state.target).then_some(now);
+            state.target = target;
+            if state.since.is_some() {
+                state.peak = 0.0;
+            }
+        }
+
+        if let Some(since) = state.since {
+            if now.duration_since(since) > OVERSHOOT_WINDOW {
+                state.since = None;
+            } else {
+                state.peak = state.peak.max(temperature - target);
+            }
         }
+    }
+
+    pub fn export(&self, name: &str) {
+        if let Some(state) = self.heaters.get(name) {
+            let labels = vec![("name", name.to_owned())];
+            gauge!("klipper.stats.heater.last_overshoot", &labels).set(state.peak);
+        }
+    }
+
+    pub fn reset(&self) {
+        self.heaters.clear();
+    }
+}
+
+impl MetricsExporter for HeaterBedStats {
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
 
         gauge!("klipper.stats.heater_bed.power", &labels).set(self.power);
         gauge!("klipper.stats.heater_bed.target", &labels).set(self.target);
         gauge!("klipper.stats.heater_bed.temperature", &labels).set(self.temperature);
+        if let Some(error) = self.temp_error() {
+            gauge!("klipper.stats.heater_bed.temp_error", &labels).set(error);
+        }
+
+        export_heater(ctx, self.power, self);
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct TemperatureSensorStats {
     temperature: f64,
-    measured_min_temp: f64,
-    measured_max_temp: f64,
+    /// Not reported by Klipper versions before 2021
+    measured_min_temp: Option<f64>,
+    measured_max_temp: Option<f64>,
 }
 
 impl MetricsExporter for TemperatureSensorStats {
-    fn export(&self, name: Option<&String>) {
-        let mut labels = Vec::new();
-        if let Some(name) = name {
-            labels.push(("name", name.to_owned()));
-        }
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
 
         gauge!("klipper.stats.temperature.current", &labels).set(self.temperature);
-        gauge!("klipper.stats.temperature.min", &labels).set(self.measured_min_temp);
-        gauge!("klipper.stats.temperature.max", &labels).set(self.measured_max_temp);
+        if let Some(min) = ctx.nullable(self.measured_min_temp) {
+            gauge!("klipper.stats.temperature.min", &labels).set(min);
+        }
+        if let Some(max) = ctx.nullable(self.measured_max_temp) {
+            gauge!("klipper.stats.temperature.max", &labels).set(max);
+        }
     }
 }
 
@@ -176,11 +543,8 @@ pub(crate) struct GenericFanStats {
 }
 
 impl MetricsExporter for GenericFanStats {
-    fn export(&self, name: Option<&String>) {
-        let mut labels = Vec::new();
-        if let Some(name) = name {
-            labels.push(("name", name.to_owned()));
-        }
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
 
         gauge!("klipper.stats.fan.speed", &labels).set(self.speed);
         gauge!("klipper.stats.fan.rpm", &labels).set(self.rpm);
@@ -189,24 +553,46 @@ impl MetricsExporter for GenericFanStats {
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct TMCStepperMotorDriver {
+    drv_status: Option<TMCDriverStatus>,
     hold_current: f64,
-    mcu_phase_offset: u64,
-    phase_offset_position: f64,
     run_current: f64,
     temperature: Option<f64>,
+    /// Whether `DRV_STATUS` of the driver contains `SG_RESULT`, which is not the
+    /// case for the TMC2208 (no StallGuard) and TMC2209 (separate register)
+    #[serde(skip)]
+    pub reports_stallguard: bool,
+}
+
+/// Fields of the `DRV_STATUS` register, Klipper only reports fields that are not zero
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(default)]
+struct TMCDriverStatus {
+    cs_actual: u64,
+    sg_result: u64,
+    otpw: u64,
+    ot: u64,
 }
 
 impl MetricsExporter for TMCStepperMotorDriver {
-    fn export(&self, name: Option<&String>) {
-        let mut labels = Vec::new();
-        if let Some(name) = name {
-            labels.push(("name", name.to_owned()));
-        }
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
 
         gauge!("klipper.stats.stepper_driver.hold_current", &labels).set(self.hold_current);
         gauge!("klipper.stats.stepper_driver.run_current", &labels).set(self.run_current);
 
-        if let Some(temperature) = self.temperature {
+        // Not available until the driver has been queried for the first time
+        if let Some(status) = &self.drv_status {
+            gauge!("klipper.stats.stepper_driver.cs_actual", &labels).set(status.cs_actual as f64);
+            gauge!("klipper.stats.stepper_driver.otpw", &labels)
+                .set((status.otpw != 0) as u8 as f64);
+            gauge!("klipper.stats.stepper_driver.ot", &labels).set((status.ot != 0) as u8 as f64);
+            if self.reports_stallguard {
+                gauge!("klipper.stats.stepper_driver.sg_result", &labels)
+                    .set(status.sg_result as f64);
+            }
+        }
+
+        if let Some(temperature) = ctx.nullable(self.temperature) {
             gauge!("klipper.stats.temperature.current", &labels).set(temperature);
         }
     }
@@ -217,7 +603,7 @@ pub(crate) struct StepperEnableStats {
     steppers: HashMap<String, bool>,
 }
 impl MetricsExporter for StepperEnableStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
         for (stepper, enabled) in &self.steppers {
             let labels = vec![("name", stepper.to_owned())];
             gauge!("klipper.stats.stepper_driver.enabled", &labels).set(*enabled as u64 as f64);
@@ -236,7 +622,7 @@ pub(crate) struct ZThermalAdjustStats {
 }
 
 impl MetricsExporter for ZThermalAdjustStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
         let labels = vec![("name", "z_adjust")];
 
         gauge!("klipper.stats.temperature.current", &labels).set(self.temperature);
@@ -256,11 +642,8 @@ pub(crate) struct FilamentRunoutSensorStats {
 }
 
 impl MetricsExporter for FilamentRunoutSensorStats {
-    fn export(&self, name: Option<&String>) {
-        let mut labels = Vec::new();
-        if let Some(name) = name {
-            labels.push(("name", name.to_owned()));
-        }
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
         gauge!("klipper.stats.filament_runout_sensor.enabled", &labels)
             .set(self.enabled as u64 as f64);
         gauge!(
@@ -271,29 +654,112 @@ impl MetricsExporter for FilamentRunoutSensorStats {
     }
 }
 
+/// Klipper only reports whether the print is paused, not why
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct PauseResumeStats {
     is_paused: bool,
 }
 
+/// `[sdcard_loop]` reports no status, the loop count only exists while a
+/// looped print runs. Supported so the object is not logged as unknown.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct SdcardLoopStats {}
+
+impl MetricsExporter for SdcardLoopStats {}
+
 impl MetricsExporter for PauseResumeStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
         gauge!("klipper.stats.pause_resume.paused").set(self.is_paused as u64 as f64);
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct ProbeStats {
-    name: String,
+    pub name: String,
     last_query: bool,
     last_z_result: f64,
 }
 
 impl MetricsExporter for ProbeStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
         let labels = vec![("name", self.name.to_owned())];
 
         gauge!("klipper.stats.probe.last_z_result", &labels).set(self.last_z_result);
+        gauge!("klipper.stats.probe.last_query", &labels).set(self.last_query as u64 as f64);
+    }
+}
+
+/// Number of recent probe results the repeatability is computed from
+const PROBE_WINDOW: usize = 10;
+
+/// The z result of a `probe at 150.000,150.000 is z=1.234568` response,
+/// which Klipper reports for every probe sample
+pub(crate) fn probe_result(response: &str) -> Option<f64> {
+    let (_, result) = response.split_once("probe at ")?;
+    let (_, z) = result.split_once(" is z=")?;
+
+    z.trim().parse().ok()
+}
+
+/// Tracks the most recent probe results of each probe to export its repeatability.
+///
+/// Results come from the console output rather than `last_z_result`, which
+/// is only reported when it changes and would drop identical results.
+#[derive(Debug, Default)]
+pub(crate) struct ProbeAccuracyTracker {
+    probes: DashMap<String, ProbeResults>,
+}
+
+#[derive(Debug, Default)]
+struct ProbeResults {
+    window: VecDeque<f64>,
+}
+
+impl ProbeResults {
+    /// Population standard deviation, as reported by `PROBE_ACCURACY`
+    fn stddev(&self) -> f64 {
+        let len = self.window.len() as f64;
+        let mean = self.window.iter().sum::<f64>() / len;
+        let variance = self.window.iter().map(|z| (z - mean).powi(2)).sum::<f64>() / len;
+
+        variance.sqrt()
+    }
+
+    fn range(&self) -> f64 {
+        let (min, max) = self
+            .window
+            .iter()
+            .fold((f64::INFINITY, f64::NEG_INFINITY), |(min, max), z| {
+                (min.min(*z), max.max(*z))
+            });
+
+        max - min
+    }
+}
+
+impl ProbeAccuracyTracker {
+    pub fn observe(&self, name: &str, z_result: f64) {
+        let mut results = self.probes.entry(name.to_owned()).or_default();
+
+        if results.window.len() == PROBE_WINDOW {
+            results.window.pop_front();
+        }
+        results.window.push_back(z_result);
+    }
+
+    pub fn export(&self, name: &str) {
+        let Some(results) = self.probes.get(name) else {
+            return;
+        };
+        if results.window.len() >= 2 {
+            let labels = vec![("name", name.to_owned())];
+            gauge!("klipper.stats.probe.z_result_stddev", &labels).set(results.stddev());
+            gauge!("klipper.stats.probe.z_result_range", &labels).set(results.range());
+        }
+    }
+
+    pub fn reset(&self) {
+        self.probes.clear();
     }
 }
 
@@ -303,7 +769,7 @@ pub(crate) struct ZTiltStats {
 }
 
 impl MetricsExporter for ZTiltStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
         gauge!("klipper.stats.z_tilt.applied").set(self.applied as u64 as f64);
     }
 }
@@ -315,7 +781,7 @@ pub(crate) struct MotionReportStats {
 }
 
 impl MetricsExporter for MotionReportStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
         gauge!("klipper.stats.motion.extruder_velocity").set(self.live_extruder_velocity);
         gauge!("klipper.stats.motion.velocity").set(self.live_velocity);
     }
@@ -328,7 +794,7 @@ pub(crate) struct ExcludeObjectStats {
 }
 
 impl MetricsExporter for ExcludeObjectStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
         gauge!("klipper.stats.exclude_objects.excluded").set(self.excluded_objects.len() as f64);
         gauge!("klipper.stats.exclude_objects.objects").set(self.objects.len() as f64);
     }
@@ -342,24 +808,55 @@ pub(crate) struct ToolheadStats {
     square_corner_velocity: f64,
     print_time: f64,
     estimated_print_time: f64,
-    stalls: u64,
+    /// Not reported by Klipper versions before 2021
+    stalls: Option<u64>,
     minimum_cruise_ratio: Option<f64>,
+    /// Homed axes, e.g. `xyz`, empty while no axis is homed
+    #[serde(default)]
+    homed_axes: String,
+    /// Commanded position as `[x, y, z, e]`
+    #[serde(default)]
+    position: Vec<f64>,
 }
 
+/// Axes reported in `toolhead.homed_axes`
+const AXES: [&str; 3] = ["x", "y", "z"];
+
 impl MetricsExporter for ToolheadStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, ctx: &ExportContext) {
         gauge!("klipper.stats.toolhead.print_time").set(self.print_time);
         gauge!("klipper.stats.toolhead.estimated_print_time").set(self.estimated_print_time);
         gauge!("klipper.stats.toolhead.max_accel").set(self.max_accel);
         gauge!("klipper.stats.toolhead.max_velocity").set(self.max_velocity);
         gauge!("klipper.stats.toolhead.square_corner_velocity").set(self.square_corner_velocity);
-        gauge!("klipper.stats.toolhead.stalls").set(self.stalls as f64);
-        if let Some(max_accel_to_decel) = self.max_accel_to_decel {
+        if let Some(max_accel_to_decel) = ctx.nullable(self.max_accel_to_decel) {
             gauge!("klipper.stats.toolhead.max_accel_to_decel").set(max_accel_to_decel);
         }
-        if let Some(minimum_cruise_ratio) = self.minimum_cruise_ratio {
+        if let Some(minimum_cruise_ratio) = ctx.nullable(self.minimum_cruise_ratio) {
             gauge!("klipper.stats.toolhead.minimum_cruise_ratio").set(minimum_cruise_ratio);
         }
+
+        let homed_axes = self.homed_axes.to_lowercase();
+        for axis in AXES {
+            let labels = vec![("axis", axis.to_owned())];
+            gauge!("klipper.stats.toolhead.homed", &labels)
+                .set(homed_axes.contains(axis) as u64 as f64);
+        }
+        let all_homed = AXES.iter().all(|axis| homed_axes.contains(axis));
+        gauge!("klipper.stats.toolhead.all_homed").set(all_homed as u64 as f64);
+
+        // Missing trailing coordinates are skipped rather than exported as zero
+        for (axis, position) in AXES.iter().chain(&["e"]).zip(&self.position) {
+            let labels = vec![("axis", axis.to_string())];
+            gauge!("klipper.stats.toolhead.position", &labels).set(*position);
+        }
+    }
+
+    fn counters(&self, _ctx: &ExportContext) -> Vec<CounterReading> {
+        self.stalls
+            .map(|stalls| ("klipper.stats.toolhead.stalls", vec![], stalls))
+            .into_iter()
+            .collect()
     }
 }
 
@@ -371,13 +868,45 @@ pub(crate) struct GCodeMoveStats {
 }
 
 impl MetricsExporter for GCodeMoveStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
         gauge!("klipper.stats.gcode.speed_factor").set(self.speed_factor);
         gauge!("klipper.stats.gcode.extrude_factor").set(self.extrude_factor);
         gauge!("klipper.stats.gcode.speed").set(self.speed);
     }
 }
 
+/// Changes smaller than this are treated as float noise rather than an override
+const FACTOR_EPSILON: f64 = 1e-6;
+
+/// Counts speed (`M220`) and flow (`M221`) override changes
+#[derive(Debug, Default)]
+pub(crate) struct GCodeMoveTracker {
+    /// Last observed `(speed_factor, extrude_factor)`
+    last: Mutex<Option<(f64, f64)>>,
+}
+
+impl GCodeMoveTracker {
+    pub fn observe(&self, stats: &GCodeMoveStats) {
+        let mut last = self.last.lock().unwrap_or_else(|e| e.into_inner());
+
+        if let Some((speed_factor, extrude_factor)) = *last {
+            if (stats.speed_factor - speed_factor).abs() > FACTOR_EPSILON {
+                counter!("klipper.stats.gcode.speed_factor_changes_total").increment(1);
+            }
+            if (stats.extrude_factor - extrude_factor).abs() > FACTOR_EPSILON {
+                counter!("klipper.stats.gcode.extrude_factor_changes_total").increment(1);
+            }
+        }
+
+        last.replace((stats.speed_factor, stats.extrude_factor));
+    }
+
+    /// Forget the last observed factors, e.g. after Klipper state was lost
+    pub fn reset(&self) {
+        self.last.lock().unwrap_or_else(|e| e.into_inner()).take();
+    }
+}
+
 #[derive(Clone, Default, Debug, Deserialize, Serialize)]
 pub(crate) struct PrintJobInfo {
     #[serde(default)]
@@ -386,8 +915,60 @@ pub(crate) struct PrintJobInfo {
     total_layer: u64,
 }
 
+/// State of the print job reported by `print_stats`, states added by newer
+/// Klipper versions are exported as unknown
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum PrintState {
+    Standby,
+    Printing,
+    Paused,
+    Complete,
+    Cancelled,
+    Error,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl PrintState {
+    /// The state as reported by Klipper
+    fn name(&self) -> &'static str {
+        match self {
+            PrintState::Standby => "standby",
+            PrintState::Printing => "printing",
+            PrintState::Paused => "paused",
+            PrintState::Complete => "complete",
+            PrintState::Cancelled => "cancelled",
+            PrintState::Error => "error",
+            PrintState::Unknown => "unknown",
+        }
+    }
+
+    /// Numeric value exported for the state
+    fn value(&self) -> f64 {
+        match self {
+            PrintState::Standby => 0.0,
+            PrintState::Printing => 1.0,
+            PrintState::Paused => 2.0,
+            PrintState::Complete => 3.0,
+            PrintState::Cancelled => 4.0,
+            PrintState::Error => 5.0,
+            PrintState::Unknown => -1.0,
+        }
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct PrintStats {
+    #[serde(default)]
+    state: PrintState,
+    /// File of the current or last print, empty before the first print
+    #[serde(default)]
+    filename: String,
+    /// Set when a print failed, e.g. with the error that cancelled it
+    #[serde(default)]
+    message: String,
     filament_used: f64,
     print_duration: f64,
     total_duration: f64,
@@ -396,16 +977,47 @@ pub(crate) struct PrintStats {
 }
 
 impl MetricsExporter for PrintStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
+        gauge!("klipper.stats.print_stats.state").set(self.state.value());
         gauge!("klipper.stats.print_stats.filament_used").set(self.filament_used);
         gauge!("klipper.stats.print_stats.print_duration").set(self.print_duration);
         gauge!("klipper.stats.print_stats.total_duration").set(self.total_duration);
 
+        // Time spent heating, paused, etc. Durations are zero until a job started.
+        if self.total_duration > 0.0 {
+            gauge!("klipper.stats.print_stats.non_print_duration")
+                .set((self.total_duration - self.print_duration).max(0.0));
+        }
+
         gauge!("klipper.stats.print_stats.current_layer").set(self.info.current_layer as f64);
         gauge!("klipper.stats.print_stats.total_layer").set(self.info.total_layer as f64);
     }
 }
 
+/// Exports `klipper.stats.print_stats.info`, labelled with the state and file of the print
+#[derive(Debug, Default)]
+pub(crate) struct PrintStatsTracker {
+    info: InfoGauge,
+}
+
+impl PrintStatsTracker {
+    pub fn observe(&self, stats: &PrintStats) {
+        self.info.set(
+            "klipper.stats.print_stats.info",
+            vec![
+                ("state", stats.state.name().to_owned()),
+                ("filename", stats.filename.to_owned()),
+                ("message", message_label(&stats.message)),
+            ],
+            1.0,
+        );
+    }
+
+    pub fn export(&self) {
+        self.info.export();
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct VirtualSdCardStats {
     file_size: u64,
@@ -415,7 +1027,7 @@ pub(crate) struct VirtualSdCardStats {
 }
 
 impl MetricsExporter for VirtualSdCardStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
         gauge!("klipper.stats.virtual_sdcard.file_size").set(self.file_size as f64);
         gauge!("klipper.stats.virtual_sdcard.file_position").set(self.file_position as f64);
         gauge!("klipper.stats.virtual_sdcard.progress").set(self.progress);
@@ -423,6 +1035,92 @@ impl MetricsExporter for VirtualSdCardStats {
     }
 }
 
+/// Stats of objects that are only meaningful in relation to each other.
+///
+/// Collected while exporting the individual objects of one cycle and exported
+/// afterwards. Derived metrics are skipped when an object they need is absent.
+#[derive(Debug, Default)]
+pub(crate) struct CorrelatedStats {
+    pub mcus: HashMap<String, McuStats>,
+    pub pause_resume: Option<PauseResumeStats>,
+    pub print_stats: Option<PrintStats>,
+    pub runout_sensors: Vec<FilamentRunoutSensorStats>,
+    pub virtual_sdcard: Option<VirtualSdCardStats>,
+}
+
+impl CorrelatedStats {
+    pub fn export(&self) {
+        export_mcu_clock_skew(&self.mcus);
+        self.export_print_state_consistency();
+        self.export_print_eta();
+        self.export_paused_by_runout();
+    }
+
+    /// Whether the print is paused while an enabled runout sensor misses filament,
+    /// i.e. most likely paused by the sensor's `pause_on_runout`
+    fn export_paused_by_runout(&self) {
+        let Some(pause_resume) = &self.pause_resume else {
+            return;
+        };
+        if self.runout_sensors.is_empty() {
+            return;
+        }
+
+        let runout = self
+            .runout_sensors
+            .iter()
+            .any(|sensor| sensor.enabled && !sensor.filament_detected);
+        gauge!("klipper.print.paused_by_runout")
+            .set((pause_resume.is_paused && runout) as u8 as f64);
+    }
+
+    /// Seconds until the print is done, extrapolated from the time it took so far
+    fn remaining_print_time(&self) -> Option<f64> {
+        let (Some(print_stats), Some(virtual_sdcard)) = (&self.print_stats, &self.virtual_sdcard)
+        else {
+            return None;
+        };
+        // Nothing to extrapolate from before the first byte was printed
+        if virtual_sdcard.progress <= 0.0 {
+            return None;
+        }
+
+        let progress = virtual_sdcard.progress.min(1.0);
+        Some(print_stats.print_duration / progress - print_stats.print_duration)
+    }
+
+    fn export_print_eta(&self) {
+        let Some(remaining) = self.remaining_print_time() else {
+            return;
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        gauge!("klipper.stats.print.remaining_seconds").set(remaining);
+        // Unix time at which the print is expected to finish
+        gauge!("klipper.stats.print.eta_seconds").set(now.as_secs_f64() + remaining);
+    }
+
+    /// The SD card is expected to be read from if and only if a print is running.
+    ///
+    /// While paused both `is_active` is false and the state is `paused`, so
+    /// they are consistent as well.
+    fn export_print_state_consistency(&self) {
+        let (Some(print_stats), Some(virtual_sdcard)) = (&self.print_stats, &self.virtual_sdcard)
+        else {
+            return;
+        };
+        if print_stats.state == PrintState::Unknown {
+            return;
+        }
+
+        let printing = print_stats.state == PrintState::Printing;
+        gauge!("klipper.print.state_consistent")
+            .set((printing == virtual_sdcard.is_active) as u8 as f64);
+    }
+}
+
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct SystemStats {
     cputime: f64,
@@ -431,7 +1129,7 @@ pub(crate) struct SystemStats {
 }
 
 impl MetricsExporter for SystemStats {
-    fn export(&self, _name: Option<&String>) {
+    fn export(&self, _ctx: &ExportContext) {
         gauge!("klipper.stats.system.cpu_time").set(self.cputime);
         gauge!("klipper.stats.system.mem_avail").set(self.memavail as f64);
         gauge!("klipper.stats.system.sys_load").set(self.sysload);
@@ -448,15 +1146,1101 @@ pub(crate) struct TemperatureFanStats {
 }
 
 impl MetricsExporter for TemperatureFanStats {
-    fn export(&self, name: Option<&String>) {
-        let mut labels = Vec::new();
-        if let Some(name) = name {
-            labels.push(("name", name.to_owned()));
-        }
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
 
         gauge!("klipper.stats.temperature_fan.speed", &labels).set(self.speed);
         gauge!("klipper.stats.temperature_fan.rpm", &labels).set(self.rpm);
         gauge!("klipper.stats.temperature_fan.target", &labels).set(self.target);
         gauge!("klipper.stats.temperature_fan.temperature", &labels).set(self.temperature);
+
+        // Without a target the fan runs in manual mode
+        if self.target > 0.0 {
+            gauge!("klipper.stats.temperature_fan.target_error", &labels)
+                .set(self.temperature - self.target);
+            gauge!("klipper.stats.temperature_fan.at_target", &labels)
+                .set(self.at_target(ctx.heater_deadband) as u8 as f64);
+        }
+    }
+}
+
+impl Heater for TemperatureFanStats {
+    fn target(&self) -> f64 {
+        self.target
+    }
+
+    fn temperature(&self) -> f64 {
+        self.temperature
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct BedMeshStats {
+    #[serde(default)]
+    profile_name: String,
+    #[serde(default)]
+    probed_matrix: Vec<Vec<f64>>,
+}
+
+impl MetricsExporter for BedMeshStats {
+    fn export(&self, ctx: &ExportContext) {
+        // Without a loaded profile Klipper reports empty values for all fields
+        if self.profile_name.is_empty() {
+            return;
+        }
+
+        let probed = self.probed_matrix.iter().flatten().copied();
+        let Some((min, max)) = probed.fold(None, |range: Option<(f64, f64)>, z| {
+            Some(range.map_or((z, z), |(min, max)| (min.min(z), max.max(z))))
+        }) else {
+            return;
+        };
+
+        let mut labels = ctx.labels();
+        labels.push(("profile", self.profile_name.clone()));
+
+        gauge!("klipper.stats.bed_mesh.probed_z_min", &labels).set(min);
+        gauge!("klipper.stats.bed_mesh.probed_z_max", &labels).set(max);
+        gauge!("klipper.stats.bed_mesh.probed_z_range", &labels).set(max - min);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct DisplayStatusStats {
+    #[serde(default)]
+    progress: f64,
+    message: Option<String>,
+}
+
+impl MetricsExporter for DisplayStatusStats {
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
+
+        gauge!("klipper.stats.display_status.progress", &labels).set(self.progress);
+    }
+}
+
+/// Exports `klipper.stats.display_status.info`, labelled with the display message
+#[derive(Debug, Default)]
+pub(crate) struct DisplayMessageTracker {
+    info: InfoGauge,
+}
+
+impl DisplayMessageTracker {
+    pub fn observe(&self, stats: &DisplayStatusStats) {
+        // A cleared message is reported as null or as an empty string
+        let message = stats
+            .message
+            .as_deref()
+            .map(message_label)
+            .unwrap_or_default();
+
+        self.info.set(
+            "klipper.stats.display_status.info",
+            vec![("message", message)],
+            1.0,
+        );
+    }
+
+    pub fn export(&self) {
+        self.info.export();
+    }
+}
+
+/// State reported by `idle_timeout`.
+///
+/// Klipper reports the state capitalized, the lowercase spellings are accepted
+/// as well.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) enum IdleTimeoutState {
+    #[serde(alias = "idle")]
+    Idle,
+    #[serde(alias = "ready")]
+    Ready,
+    #[serde(alias = "printing")]
+    Printing,
+    #[default]
+    #[serde(other)]
+    Unknown,
+}
+
+impl IdleTimeoutState {
+    /// Numeric value exported for the state, `-1` if it is not known
+    fn value(&self) -> f64 {
+        match self {
+            IdleTimeoutState::Idle => 0.0,
+            IdleTimeoutState::Ready => 1.0,
+            IdleTimeoutState::Printing => 2.0,
+            IdleTimeoutState::Unknown => -1.0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct IdleTimeoutStats {
+    #[serde(default)]
+    state: IdleTimeoutState,
+    printing_time: f64,
+}
+
+impl MetricsExporter for IdleTimeoutStats {
+    fn export(&self, _ctx: &ExportContext) {
+        gauge!("klipper.stats.idle_timeout.printing_time").set(self.printing_time);
+        gauge!("klipper.stats.idle_timeout.state").set(self.state.value());
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct FirmwareRetractionStats {
+    retract_length: f64,
+    retract_speed: f64,
+    unretract_extra_length: f64,
+    unretract_speed: f64,
+}
+
+impl MetricsExporter for FirmwareRetractionStats {
+    fn export(&self, _ctx: &ExportContext) {
+        gauge!("klipper.stats.firmware_retraction.retract_length").set(self.retract_length);
+        gauge!("klipper.stats.firmware_retraction.retract_speed").set(self.retract_speed);
+        gauge!("klipper.stats.firmware_retraction.unretract_extra_length")
+            .set(self.unretract_extra_length);
+        gauge!("klipper.stats.firmware_retraction.unretract_speed").set(self.unretract_speed);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct OutputPinStats {
+    value: f64,
+}
+
+impl MetricsExporter for OutputPinStats {
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
+
+        gauge!("klipper.stats.output_pin.value", &labels).set(self.value);
+    }
+}
+
+/// Position of a `servo`, the PWM duty cycle of the last requested pulse width
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ServoStats {
+    /// `null` in some setups until the servo was moved for the first time
+    #[serde(default)]
+    value: Option<f64>,
+}
+
+impl MetricsExporter for ServoStats {
+    fn export(&self, ctx: &ExportContext) {
+        if let Some(value) = ctx.nullable(self.value) {
+            gauge!("klipper.stats.servo.value", &ctx.labels()).set(value);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct QuadGantryLevelStats {
+    applied: bool,
+}
+
+impl MetricsExporter for QuadGantryLevelStats {
+    fn export(&self, _ctx: &ExportContext) {
+        gauge!("klipper.stats.quad_gantry_level.applied").set(self.applied as u64 as f64);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ScrewAdjustment {
+    z: f64,
+    /// Direction to turn the screw, `CW` or `CCW`
+    sign: String,
+    /// Amount to turn the screw as `turns:minutes`, a minute being 1/60 of a turn
+    adjust: String,
+    is_base: bool,
+}
+
+impl ScrewAdjustment {
+    /// Turns required to level this screw, clockwise turns are positive
+    fn turns(&self) -> Option<f64> {
+        let (turns, minutes) = self.adjust.split_once(':')?;
+        let turns = turns.trim().parse::<f64>().ok()? + minutes.trim().parse::<f64>().ok()? / 60.0;
+
+        match self.sign.as_str() {
+            "CW" => Some(turns),
+            "CCW" => Some(-turns),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ScrewsTiltAdjustStats {
+    #[serde(default)]
+    error: bool,
+    max_deviation: Option<f64>,
+    #[serde(default)]
+    results: HashMap<String, ScrewAdjustment>,
+}
+
+impl MetricsExporter for ScrewsTiltAdjustStats {
+    fn export(&self, ctx: &ExportContext) {
+        gauge!("klipper.stats.screws_tilt_adjust.error").set(self.error as u8 as f64);
+
+        if let Some(max_deviation) = ctx.nullable(self.max_deviation) {
+            gauge!("klipper.stats.screws_tilt_adjust.max_deviation").set(max_deviation);
+        }
+
+        for (screw, result) in &self.results {
+            let Some(turns) = result.turns() else {
+                tracing::debug!(screw, adjust = result.adjust, "Unexpected screw adjustment");
+                continue;
+            };
+            gauge!(
+                "klipper.stats.screws_tilt_adjust.adjust",
+                &[("screw", screw.to_owned())]
+            )
+            .set(turns);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ExtruderStepperStats {
+    pressure_advance: f64,
+    smooth_time: f64,
+}
+
+impl MetricsExporter for ExtruderStepperStats {
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
+
+        gauge!("klipper.stats.extruder_stepper.pressure_advance", &labels)
+            .set(self.pressure_advance);
+        gauge!("klipper.stats.extruder_stepper.smooth_time", &labels).set(self.smooth_time);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct LedStats {
+    /// `[red, green, blue, white]` of every pixel, each between 0 and 1
+    color_data: Vec<Vec<f64>>,
+}
+
+impl LedStats {
+    /// Mean value of a color channel across all pixels
+    fn channel(&self, index: usize) -> Option<f64> {
+        let values = self
+            .color_data
+            .iter()
+            .filter_map(|pixel| pixel.get(index))
+            .collect::<Vec<_>>();
+        (!values.is_empty()).then(|| values.iter().copied().sum::<f64>() / values.len() as f64)
+    }
+}
+
+impl MetricsExporter for LedStats {
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
+
+        for (index, channel) in ["red", "green", "blue", "white"].iter().enumerate() {
+            if let Some(value) = self.channel(index) {
+                gauge!(format!("klipper.stats.led.{channel}"), &labels).set(value);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct GcodeButtonStats {
+    state: String,
+}
+
+impl MetricsExporter for GcodeButtonStats {
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
+
+        gauge!("klipper.stats.gcode_button.pressed", &labels)
+            .set((self.state == "PRESSED") as u64 as f64);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct GcodeMacroStats {
+    /// The `variable_*` of the macro, without the prefix
+    #[serde(flatten)]
+    variables: HashMap<String, serde_json::Value>,
+}
+
+impl MetricsExporter for GcodeMacroStats {
+    fn export(&self, ctx: &ExportContext) {
+        let name = ctx.name.unwrap_or_default().to_owned();
+
+        for (variable, value) in &self.variables {
+            if let Some(value) = value.as_f64() {
+                let labels = vec![("macro", name.clone()), ("variable", variable.to_owned())];
+                gauge!("klipper.stats.gcode_macro.variable", &labels).set(value);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ManualProbeStats {
+    is_active: bool,
+    z_position: Option<f64>,
+}
+
+impl MetricsExporter for ManualProbeStats {
+    fn export(&self, ctx: &ExportContext) {
+        gauge!("klipper.stats.manual_probe.active").set(self.is_active as u64 as f64);
+        if let Some(z_position) = ctx.nullable(self.z_position) {
+            gauge!("klipper.stats.manual_probe.z_position").set(z_position);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct BedScrewsStats {
+    is_active: bool,
+    /// `adjust` or `fine` while a `BED_SCREWS_ADJUST` session is running
+    state: Option<String>,
+    current_screw: u64,
+    accepted_screws: u64,
+}
+
+impl MetricsExporter for BedScrewsStats {
+    fn export(&self, _ctx: &ExportContext) {
+        gauge!("klipper.stats.bed_screws.active").set(self.is_active as u64 as f64);
+        gauge!("klipper.stats.bed_screws.accepted").set(self.accepted_screws as f64);
+        gauge!("klipper.stats.bed_screws.current_screw").set(self.current_screw as f64);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct InputShaperStats {
+    shaper_type_x: String,
+    shaper_freq_x: f64,
+    damping_ratio_x: Option<f64>,
+    shaper_type_y: String,
+    shaper_freq_y: f64,
+    damping_ratio_y: Option<f64>,
+}
+
+impl MetricsExporter for InputShaperStats {
+    fn export(&self, ctx: &ExportContext) {
+        let axes = [
+            ("x", self.shaper_freq_x, self.damping_ratio_x),
+            ("y", self.shaper_freq_y, self.damping_ratio_y),
+        ];
+        for (axis, freq, damping_ratio) in axes {
+            let labels = vec![("axis", axis.to_owned())];
+            gauge!("klipper.stats.input_shaper.freq", &labels).set(freq);
+            if let Some(damping_ratio) = ctx.nullable(damping_ratio) {
+                gauge!("klipper.stats.input_shaper.damping_ratio", &labels).set(damping_ratio);
+            }
+        }
+    }
+}
+
+/// Exports `klipper.stats.input_shaper.info`, labelled with the shaper type of each axis
+#[derive(Debug, Default)]
+pub(crate) struct InputShaperTracker {
+    info: InfoGauge,
+}
+
+impl InputShaperTracker {
+    pub fn observe(&self, stats: &InputShaperStats) {
+        self.info.set(
+            "klipper.stats.input_shaper.info",
+            vec![
+                ("shaper_type_x", stats.shaper_type_x.to_owned()),
+                ("shaper_type_y", stats.shaper_type_y.to_owned()),
+            ],
+            1.0,
+        );
+    }
+
+    pub fn export(&self) {
+        self.info.export();
+    }
+}
+
+/// Accelerometers report no status, the object is only exported as present
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Adxl345Stats {}
+
+impl MetricsExporter for Adxl345Stats {
+    fn export(&self, ctx: &ExportContext) {
+        gauge!("klipper.stats.adxl345.present", &ctx.labels()).set(1.0);
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct EddyProbeSample {
+    temp: Option<f64>,
+    dist: Option<f64>,
+}
+
+/// Third-party eddy current probes (Beacon, Cartographer), all fields depend
+/// on the probe firmware version
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct EddyProbeStats {
+    last_z_result: Option<f64>,
+    /// Coil temperature, reported in `last_sample` by most firmware versions
+    temp: Option<f64>,
+    last_sample: Option<EddyProbeSample>,
+    /// The Klipper object, `beacon` or `cartographer`
+    #[serde(skip)]
+    pub probe: String,
+}
+
+impl MetricsExporter for EddyProbeStats {
+    fn export(&self, ctx: &ExportContext) {
+        let labels = vec![("name", self.probe.to_owned())];
+        let sample = self.last_sample.clone().unwrap_or_default();
+
+        if let Some(last_z_result) = ctx.nullable(self.last_z_result) {
+            gauge!("klipper.stats.eddy_probe.last_z_result", &labels).set(last_z_result);
+        }
+        if let Some(temperature) = ctx.nullable(self.temp.or(sample.temp)) {
+            gauge!("klipper.stats.eddy_probe.temperature", &labels).set(temperature);
+        }
+        if let Some(distance) = ctx.nullable(sample.dist) {
+            gauge!("klipper.stats.eddy_probe.distance", &labels).set(distance);
+        }
+    }
+}
+
+/// Load cell probes, the force is only known once the load cell is calibrated
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct LoadCellStats {
+    #[serde(default)]
+    is_calibrated: Option<bool>,
+    /// Force in grams, named `force_g` by current Klipper versions
+    #[serde(default, alias = "force_g")]
+    force: Option<f64>,
+    #[serde(default)]
+    counts: Option<f64>,
+}
+
+impl MetricsExporter for LoadCellStats {
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
+        let calibrated = self.is_calibrated.unwrap_or_default();
+
+        gauge!("klipper.stats.load_cell.calibrated", &labels).set(calibrated as u64 as f64);
+        if let Some(force) = ctx.nullable(self.force.filter(|_| calibrated)) {
+            gauge!("klipper.stats.load_cell.force", &labels).set(force);
+        }
+        if let Some(counts) = ctx.nullable(self.counts) {
+            gauge!("klipper.stats.load_cell.counts", &labels).set(counts);
+        }
+    }
+}
+
+/// State of every endstop as returned by `printer.query_endstops.status`,
+/// e.g. `{"x": "TRIGGERED", "y": "open"}`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(transparent)]
+pub(crate) struct EndstopsStats {
+    endstops: HashMap<String, String>,
+}
+
+impl MetricsExporter for EndstopsStats {
+    fn export(&self, _ctx: &ExportContext) {
+        for (endstop, state) in &self.endstops {
+            let triggered = state.eq_ignore_ascii_case("triggered");
+            gauge!("klipper.endstop.triggered", "endstop" => endstop.to_owned())
+                .set(f64::from(triggered));
+        }
+    }
+}
+
+/// Identity of the Klipper host as reported by `printer.info`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct PrinterInfoStats {
+    #[serde(default)]
+    hostname: String,
+    #[serde(default)]
+    software_version: String,
+    #[serde(default)]
+    cpu_info: String,
+}
+
+impl MetricsExporter for PrinterInfoStats {}
+
+/// Exports `klipper.info`, labelled with the hostname and Klipper version
+#[derive(Debug, Default)]
+pub(crate) struct PrinterInfoTracker {
+    info: InfoGauge,
+}
+
+impl PrinterInfoTracker {
+    pub fn observe(&self, stats: &PrinterInfoStats) {
+        self.info.set(
+            "klipper.info",
+            vec![
+                ("hostname", stats.hostname.to_owned()),
+                ("software_version", stats.software_version.to_owned()),
+                ("cpu_info", stats.cpu_info.to_owned()),
+            ],
+            1.0,
+        );
+    }
+
+    pub fn export(&self) {
+        self.info.export();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::tests::{render, samples};
+    use serde_json::json;
+
+    fn mcu() -> McuStats {
+        serde_json::from_value(json!({
+            "bytes_write": 1024,
+            "freq": 64000000,
+            "mcu_awake": 0.01,
+            "mcu_task_avg": 0.00002,
+            "mcu_task_stddev": 0.00001,
+            "ready_bytes": 0,
+            "upcoming_bytes": 12,
+            "send_seq": 100,
+            "receive_seq": 100,
+            "retransmit_seq": 0,
+            "srtt": 0.001,
+            "rto": 0.025,
+            "rttvar": 0.0005,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn mcu_descriptions_match_exported_gauges() {
+        let mcu = mcu();
+        let output = render(|| {
+            mcu.describe();
+            mcu.export(&ExportContext::default());
+        });
+
+        for name in ["ready_bytes", "upcoming_bytes"] {
+            let name = format!("klipper_stats_mcu_{name}");
+            assert!(
+                output.contains(&format!("# TYPE {name} gauge")),
+                "{name} is not exported as gauge:\n{output}"
+            );
+            assert!(
+                output.contains(&format!("# HELP {name} ")),
+                "{name} is not described:\n{output}"
+            );
+        }
+        assert!(!output.contains("upcomping"), "{output}");
+    }
+
+    #[test]
+    fn extruder_descriptions_match_exported_gauges() {
+        let extruder: ExtruderStats = serde_json::from_value(json!({
+            "can_extrude": true,
+            "power": 0.5,
+            "pressure_advance": 0.04,
+            "smooth_time": 0.04,
+            "target": 240.0,
+            "temperature": 239.5,
+        }))
+        .unwrap();
+        let output = render(|| {
+            extruder.describe();
+            extruder.export(&ExportContext::default());
+        });
+
+        for name in ["smooth_time", "smooth_tmime"] {
+            let name = format!("klipper_stats_extruder_{name}");
+            assert!(
+                output.contains(&format!("# TYPE {name} gauge")),
+                "{name} is not exported as gauge:\n{output}"
+            );
+            assert!(
+                output.contains(&format!("# HELP {name} ")),
+                "{name} is not described:\n{output}"
+            );
+        }
+    }
+
+    fn screw(sign: &str, adjust: &str) -> ScrewAdjustment {
+        ScrewAdjustment {
+            z: 2.5,
+            sign: sign.to_owned(),
+            adjust: adjust.to_owned(),
+            is_base: false,
+        }
+    }
+
+    #[test]
+    fn unexpected_klippy_states_are_unknown() {
+        let webhooks = |state: &str| -> WebhooksStats {
+            serde_json::from_value(json!({"state": state, "state_message": ""})).unwrap()
+        };
+
+        assert_eq!(webhooks("ready").state.value(), 1.0);
+        assert_eq!(webhooks("Shutdown").state.value(), 2.0);
+        assert_eq!(webhooks("reconnecting").state.value(), -1.0);
+        assert_eq!(webhooks("reconnecting").state.name(), "unknown");
+    }
+
+    #[test]
+    fn fields_missing_in_older_klipper_versions_are_skipped() {
+        let ctx = ExportContext {
+            name: Some("mcu"),
+            ..Default::default()
+        };
+        // Klipper v0.9 reported neither `ready_bytes` nor `upcoming_bytes`
+        let mcu: McuStats = serde_json::from_value(json!({
+            "bytes_write": 1024,
+            "bytes_read": 2048,
+            "freq": 16000000,
+            "mcu_awake": 0.01,
+            "mcu_task_avg": 0.00002,
+            "mcu_task_stddev": 0.00001,
+            "send_seq": 100,
+            "receive_seq": 100,
+            "srtt": 0.001,
+        }))
+        .unwrap();
+        let output = render(|| mcu.export(&ctx));
+        assert_eq!(mcu.counters(&ctx).len(), 4);
+        assert!(!output.contains("klipper_stats_mcu_upcoming_bytes"));
+        assert!(!output.contains("klipper_stats_mcu_rto"));
+
+        let toolhead: ToolheadStats = serde_json::from_value(json!({
+            "max_accel": 3000.0,
+            "max_accel_to_decel": 1500.0,
+            "max_velocity": 300.0,
+            "square_corner_velocity": 5.0,
+            "print_time": 12.5,
+            "estimated_print_time": 12.0,
+        }))
+        .unwrap();
+        let output = render(|| toolhead.export(&ExportContext::default()));
+        assert!(output.contains("klipper_stats_toolhead_max_accel 3000"));
+        assert!(toolhead.counters(&ExportContext::default()).is_empty());
+
+        let sensor: TemperatureSensorStats =
+            serde_json::from_value(json!({"temperature": 35.0})).unwrap();
+        let output = render(|| sensor.export(&ctx));
+        assert_eq!(
+            samples(&output),
+            vec!["klipper_stats_temperature_current{name=\"mcu\"} 35"]
+        );
+
+        // Extruders without a stepper report no pressure advance
+        let extruder: ExtruderStats = serde_json::from_value(json!({
+            "can_extrude": false,
+            "power": 0.0,
+            "target": 0.0,
+            "temperature": 22.0,
+        }))
+        .unwrap();
+        let output = render(|| extruder.export(&ctx));
+        assert!(output.contains("klipper_stats_extruder_temperature{name=\"mcu\"} 22"));
+        assert!(!output.contains("pressure_advance"));
+
+        // The phase offset is `null` until the stepper is enabled
+        let driver: Result<TMCStepperMotorDriver, _> = serde_json::from_value(json!({
+            "drv_status": null,
+            "hold_current": 0.5,
+            "mcu_phase_offset": null,
+            "phase_offset_position": null,
+            "run_current": 0.8,
+            "temperature": null,
+        }));
+        assert!(driver.is_ok());
+    }
+
+    #[test]
+    fn screw_adjustment_is_converted_to_signed_turns() {
+        assert_eq!(screw("CW", "00:00").turns(), Some(0.0));
+        assert_eq!(screw("CW", "01:30").turns(), Some(1.5));
+        assert_eq!(screw("CCW", "00:15").turns(), Some(-0.25));
+        assert_eq!(screw("CW", "garbage").turns(), None);
+        assert_eq!(screw("sideways", "00:15").turns(), None);
+    }
+
+    #[test]
+    fn tmc_driver_status_defaults_to_zero() {
+        let mut driver: TMCStepperMotorDriver = serde_json::from_value(json!({
+            "drv_status": { "cs_actual": 16, "otpw": 1 },
+            "hold_current": 0.5,
+            "mcu_phase_offset": 0,
+            "phase_offset_position": 0.0,
+            "run_current": 0.8,
+            "temperature": null,
+        }))
+        .unwrap();
+        driver.reports_stallguard = true;
+        let output = render(|| driver.export(&ExportContext::default()));
+
+        let samples = crate::types::tests::samples(&output);
+        for sample in [
+            "klipper_stats_stepper_driver_cs_actual 16",
+            "klipper_stats_stepper_driver_otpw 1",
+            "klipper_stats_stepper_driver_ot 0",
+            "klipper_stats_stepper_driver_sg_result 0",
+        ] {
+            assert!(samples.contains(&sample), "{sample} missing:\n{output}");
+        }
+    }
+
+    #[test]
+    fn led_channels_are_averaged_across_pixels() {
+        let leds: LedStats = serde_json::from_value(json!({
+            "color_data": [[1.0, 0.0, 0.5, 0.0], [0.0, 0.0, 0.5, 0.0]],
+        }))
+        .unwrap();
+
+        assert_eq!(leds.channel(0), Some(0.5));
+        assert_eq!(leds.channel(2), Some(0.5));
+        assert_eq!(LedStats { color_data: vec![] }.channel(0), None);
+    }
+
+    #[test]
+    fn only_numeric_macro_variables_are_exported() {
+        let stats: GcodeMacroStats = serde_json::from_value(json!({
+            "bed_temp": 60,
+            "offset": -0.05,
+            "material": "PLA",
+            "enabled": true,
+        }))
+        .unwrap();
+        let ctx = ExportContext {
+            name: Some("PRINT_START"),
+            ..Default::default()
+        };
+        let output = render(|| stats.export(&ctx));
+
+        let name = "klipper_stats_gcode_macro_variable";
+        assert_eq!(
+            crate::types::tests::samples(&output),
+            vec![
+                format!("{name}{{macro=\"PRINT_START\",variable=\"bed_temp\"}} 60"),
+                format!("{name}{{macro=\"PRINT_START\",variable=\"offset\"}} -0.05"),
+            ]
+        );
+    }
+
+    #[test]
+    fn remaining_print_time_is_extrapolated_from_progress() {
+        let stats = |progress: f64| CorrelatedStats {
+            print_stats: serde_json::from_value(json!({
+                "filament_used": 0.0,
+                "print_duration": 600.0,
+                "total_duration": 660.0,
+            }))
+            .ok(),
+            virtual_sdcard: Some(VirtualSdCardStats {
+                file_size: 1000,
+                file_position: (progress * 1000.0) as u64,
+                progress,
+                is_active: true,
+            }),
+            ..Default::default()
+        };
+
+        assert_eq!(stats(0.25).remaining_print_time(), Some(1800.0));
+        assert_eq!(stats(1.0).remaining_print_time(), Some(0.0));
+        assert_eq!(stats(0.0).remaining_print_time(), None);
+        assert_eq!(CorrelatedStats::default().remaining_print_time(), None);
+    }
+
+    #[test]
+    fn probe_repeatability_includes_identical_results() {
+        let tracker = ProbeAccuracyTracker::default();
+        let responses = [
+            "// probe at 150.000,150.000 is z=1.000000",
+            "// probe at 150.000,150.000 is z=1.000000",
+            "// Result is z=1.000000",
+            "// probe at 150.000,150.000 is z=1.500000",
+            "// probe at 150.000,150.000 is z=2.000000",
+        ];
+
+        let output = render(|| {
+            for z in responses.into_iter().filter_map(probe_result) {
+                tracker.observe("probe", z);
+            }
+            tracker.export("probe");
+        });
+
+        let mut stddev = output
+            .lines()
+            .find(|line| line.starts_with("klipper_stats_probe_z_result_stddev"))
+            .unwrap()
+            .rsplit(' ');
+        let stddev: f64 = stddev.next().unwrap().parse().unwrap();
+        assert!((stddev - 0.171875f64.sqrt()).abs() < 1e-9, "{output}");
+        assert!(
+            output.contains("klipper_stats_probe_z_result_range{name=\"probe\"} 1"),
+            "{output}"
+        );
+    }
+
+    fn toolhead(homed_axes: &str) -> ToolheadStats {
+        serde_json::from_value(json!({
+            "max_accel": 3000.0,
+            "max_velocity": 300.0,
+            "square_corner_velocity": 5.0,
+            "print_time": 12.5,
+            "estimated_print_time": 12.0,
+            "stalls": 0,
+            "homed_axes": homed_axes,
+            "position": [120.0, 80.5, 0.2],
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn homed_axes_are_exported_per_axis() {
+        let output = render(|| toolhead("xy").export(&ExportContext::default()));
+
+        for expected in [
+            "klipper_stats_toolhead_homed{axis=\"x\"} 1",
+            "klipper_stats_toolhead_homed{axis=\"y\"} 1",
+            "klipper_stats_toolhead_homed{axis=\"z\"} 0",
+            "klipper_stats_toolhead_all_homed 0",
+        ] {
+            assert!(output.contains(expected), "{expected} missing:\n{output}");
+        }
+
+        let output = render(|| toolhead("xyz").export(&ExportContext::default()));
+        assert!(
+            output.contains("klipper_stats_toolhead_all_homed 1"),
+            "{output}"
+        );
+    }
+
+    #[test]
+    fn short_toolhead_positions_are_exported_partially() {
+        let output = render(|| toolhead("xyz").export(&ExportContext::default()));
+
+        for expected in [
+            "klipper_stats_toolhead_position{axis=\"x\"} 120",
+            "klipper_stats_toolhead_position{axis=\"y\"} 80.5",
+            "klipper_stats_toolhead_position{axis=\"z\"} 0.2",
+        ] {
+            assert!(output.contains(expected), "{expected} missing:\n{output}");
+        }
+        assert!(!output.contains("axis=\"e\""), "{output}");
+    }
+
+    #[test]
+    fn input_shaper_is_exported_per_axis() {
+        let stats: InputShaperStats = serde_json::from_value(json!({
+            "shaper_type_x": "mzv",
+            "shaper_freq_x": 56.2,
+            "damping_ratio_x": 0.1,
+            "shaper_type_y": "ei",
+            "shaper_freq_y": 41.0,
+            "damping_ratio_y": 0.1,
+        }))
+        .unwrap();
+        let tracker = InputShaperTracker::default();
+
+        let output = render(|| {
+            stats.export(&ExportContext::default());
+            tracker.observe(&stats);
+        });
+
+        for expected in [
+            "klipper_stats_input_shaper_freq{axis=\"x\"} 56.2",
+            "klipper_stats_input_shaper_freq{axis=\"y\"} 41",
+            "klipper_stats_input_shaper_damping_ratio{axis=\"x\"} 0.1",
+            "klipper_stats_input_shaper_info{shaper_type_x=\"mzv\",shaper_type_y=\"ei\"} 1",
+        ] {
+            assert!(output.contains(expected), "{expected} missing:\n{output}");
+        }
+    }
+
+    #[test]
+    fn eddy_probe_fields_are_optional() {
+        let probe = |data: serde_json::Value| -> EddyProbeStats {
+            let mut stats: EddyProbeStats = serde_json::from_value(data).unwrap();
+            stats.probe = "beacon".to_owned();
+            stats
+        };
+
+        let output = render(|| {
+            probe(json!({
+                "last_z_result": 0.012,
+                "last_sample": {"time": 1.5, "value": 3000000.0, "temp": 45.5, "dist": 2.1},
+            }))
+            .export(&ExportContext::default())
+        });
+        for expected in [
+            "klipper_stats_eddy_probe_last_z_result{name=\"beacon\"} 0.012",
+            "klipper_stats_eddy_probe_temperature{name=\"beacon\"} 45.5",
+            "klipper_stats_eddy_probe_distance{name=\"beacon\"} 2.1",
+        ] {
+            assert!(output.contains(expected), "{expected} missing:\n{output}");
+        }
+
+        let output = render(|| probe(json!({"temp": 38.0})).export(&ExportContext::default()));
+        assert_eq!(
+            samples(&output),
+            vec!["klipper_stats_eddy_probe_temperature{name=\"beacon\"} 38"]
+        );
+    }
+
+    #[test]
+    fn load_cell_force_requires_calibration() {
+        let ctx = ExportContext {
+            name: Some("load_cell"),
+            ..Default::default()
+        };
+        let load_cell =
+            |data: serde_json::Value| -> LoadCellStats { serde_json::from_value(data).unwrap() };
+
+        let output = render(|| {
+            load_cell(json!({"is_calibrated": true, "force_g": 12.5, "counts": 2048})).export(&ctx)
+        });
+        assert_eq!(
+            samples(&output),
+            vec![
+                "klipper_stats_load_cell_calibrated{name=\"load_cell\"} 1",
+                "klipper_stats_load_cell_counts{name=\"load_cell\"} 2048",
+                "klipper_stats_load_cell_force{name=\"load_cell\"} 12.5",
+            ]
+        );
+
+        let output = render(|| {
+            load_cell(json!({"is_calibrated": null, "force_g": null, "counts": 2048})).export(&ctx)
+        });
+        assert_eq!(
+            samples(&output),
+            vec![
+                "klipper_stats_load_cell_calibrated{name=\"load_cell\"} 0",
+                "klipper_stats_load_cell_counts{name=\"load_cell\"} 2048",
+            ]
+        );
+    }
+
+    #[test]
+    fn mcu_rtt_is_recorded_as_histogram() {
+        let mcu: McuStats =
+            serde_json::from_value(json!({ "srtt": 0.001, "rttvar": 0.0005 })).unwrap();
+        let output = render(|| {
+            mcu.record_rtt("mcu");
+            mcu.record_rtt("mcu");
+        });
+
+        assert!(output.contains("klipper_stats_mcu_srtt_seconds_count{name=\"mcu\"} 2"));
+        assert!(output.contains("klipper_stats_mcu_rttvar_seconds_count{name=\"mcu\"} 2"));
+        assert!(!output.contains("klipper_stats_mcu_rto_seconds"));
+    }
+
+    #[test]
+    fn endstops_are_triggered_or_open() {
+        let endstops: EndstopsStats = serde_json::from_value(json!({
+            "x": "TRIGGERED",
+            "y": "open",
+            "z": "open"
+        }))
+        .unwrap();
+        let output = render(|| endstops.export(&ExportContext::default()));
+
+        assert_eq!(
+            samples(&output),
+            vec![
+                "klipper_endstop_triggered{endstop=\"x\"} 1",
+                "klipper_endstop_triggered{endstop=\"y\"} 0",
+                "klipper_endstop_triggered{endstop=\"z\"} 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn print_state_is_exported_with_the_file() {
+        let print_stats = |state: &str| -> PrintStats {
+            serde_json::from_value(json!({
+                "state": state,
+                "filename": "benchy.gcode",
+                "message": "",
+                "filament_used": 120.5,
+                "print_duration": 300.0,
+                "total_duration": 360.0
+            }))
+            .unwrap()
+        };
+        let tracker = PrintStatsTracker::default();
+        let output = render(|| {
+            for stats in [print_stats("printing"), print_stats("paused")] {
+                stats.export(&ExportContext::default());
+                tracker.observe(&stats);
+            }
+        });
+        let state: Vec<_> = samples(&output)
+            .into_iter()
+            .filter(|sample| {
+                sample.starts_with("klipper_stats_print_stats_state")
+                    || sample.starts_with("klipper_stats_print_stats_info")
+            })
+            .collect();
+
+        assert_eq!(
+            state,
+            vec![
+                "klipper_stats_print_stats_info{state=\"paused\",filename=\"benchy.gcode\",message=\"\"} 1",
+                "klipper_stats_print_stats_info{state=\"printing\",filename=\"benchy.gcode\",message=\"\"} 0",
+                "klipper_stats_print_stats_state 2",
+            ]
+        );
+        assert_eq!(print_stats("resuming").state.value(), -1.0);
+    }
+
+    #[test]
+    fn servo_value_is_skipped_until_it_moved() {
+        let ctx = ExportContext {
+            name: Some("probe"),
+            ..Default::default()
+        };
+        let servo =
+            |data: serde_json::Value| -> ServoStats { serde_json::from_value(data).unwrap() };
+
+        assert_eq!(
+            samples(&render(|| servo(json!({ "value": 0.075 })).export(&ctx))),
+            vec!["klipper_stats_servo_value{name=\"probe\"} 0.075"]
+        );
+        assert!(render(|| servo(json!({ "value": null })).export(&ctx)).is_empty());
+        assert!(render(|| servo(json!({})).export(&ctx)).is_empty());
+    }
+
+    #[test]
+    fn runout_pauses_are_detected() {
+        let sensor = |enabled: bool, filament_detected: bool| FilamentRunoutSensorStats {
+            enabled,
+            filament_detected,
+        };
+        let paused_by_runout = |is_paused: bool, runout_sensors| {
+            let stats = CorrelatedStats {
+                pause_resume: Some(PauseResumeStats { is_paused }),
+                runout_sensors,
+                ..Default::default()
+            };
+            samples(&render(|| stats.export_paused_by_runout())).join("\n")
+        };
+
+        assert_eq!(
+            paused_by_runout(true, vec![sensor(true, true), sensor(true, false)]),
+            "klipper_print_paused_by_runout 1"
+        );
+        assert_eq!(
+            paused_by_runout(false, vec![sensor(true, false)]),
+            "klipper_print_paused_by_runout 0"
+        );
+        // A disabled sensor does not pause the print
+        assert_eq!(
+            paused_by_runout(true, vec![sensor(false, false)]),
+            "klipper_print_paused_by_runout 0"
+        );
+        assert_eq!(paused_by_runout(true, vec![]), "");
     }
 }
@@ -1,8 +1,192 @@
 pub(crate) mod klipper;
 pub(crate) mod moonraker;
 
+use dashmap::DashMap;
+use metrics::counter;
+use std::collections::HashMap;
+
+/// Settings shared by all exporters during one export cycle.
+///
+/// Labels and name changes that apply to every series (`--instance-name`,
+/// `--drop-metric-prefix`) are handled by the recorder layers in
+/// [`crate::recorder`] instead, so they also cover metrics emitted outside of
+/// an exporter.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct ExportContext<'a> {
+    /// Identifier of the exported object, e.g. `extruder1`
+    pub name: Option<&'a str>,
+    /// Export missing optional values as NaN instead of skipping the metric
+    pub null_as_nan: bool,
+    /// Maximum deviation from the target temperature still considered "at target"
+    pub heater_deadband: f64,
+}
+
+impl ExportContext<'_> {
+    /// Labels identifying the exported object
+    pub fn labels(&self) -> Vec<(&'static str, String)> {
+        self.name
+            .map(|name| vec![("name", name.to_owned())])
+            .unwrap_or_default()
+    }
+
+    /// The value to export for an optional field, `None` if the metric should be skipped
+    pub fn nullable(&self, value: Option<f64>) -> Option<f64> {
+        value.or_else(|| self.null_as_nan.then_some(f64::NAN))
+    }
+}
+
 pub(crate) trait MetricsExporter {
     #[allow(dead_code)]
     fn describe(&self) {}
-    fn export(&self, _name: Option<&String>) {}
+    fn export(&self, _ctx: &ExportContext) {}
+    /// Counters as read from Klipper or Moonraker, exported by a [`CounterTracker`]
+    fn counters(&self, _ctx: &ExportContext) -> Vec<CounterReading> {
+        Vec::new()
+    }
+}
+
+type Labels = Vec<(&'static str, String)>;
+
+/// Name, labels and current value of a counter kept by Klipper or Moonraker
+pub(crate) type CounterReading = (&'static str, Labels, u64);
+
+/// Exports counters kept by Klipper or Moonraker so they survive restarts.
+///
+/// The counters start at zero again after e.g. a `FIRMWARE_RESTART`, but
+/// `Counter::absolute` never moves backwards, so the exported value would stall
+/// until the count passes the previous maximum. The last reading of every series
+/// is tracked instead and a decrease is treated as a reset, which Prometheus'
+/// `rate()` then handles like a restart of the exporter itself.
+///
+/// Readings are kept per object and replaced with every observation, so series
+/// no longer reported by an object, e.g. a removed network interface, are dropped.
+#[derive(Debug, Default)]
+pub(crate) struct CounterTracker {
+    last: DashMap<String, HashMap<(&'static str, Labels), u64>>,
+}
+
+impl CounterTracker {
+    pub fn observe(&self, object: &str, readings: Vec<CounterReading>) {
+        let mut last = self.last.entry(object.to_owned()).or_default();
+        let previous = std::mem::take(&mut *last);
+
+        for (name, labels, value) in readings {
+            let increment = match previous.get(&(name, labels.clone())) {
+                Some(&previous) if value >= previous => value - previous,
+                _ => value,
+            };
+            counter!(name, &labels).increment(increment);
+            last.insert((name, labels), value);
+        }
+    }
+
+    /// Forget the readings of objects that no longer exist
+    pub fn retain(&self, mut keep: impl FnMut(&str) -> bool) {
+        self.last.retain(|object, _| keep(object));
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use metrics_exporter_prometheus::PrometheusBuilder;
+    use serde_json::json;
+
+    /// Render everything emitted by `f` in the Prometheus text format
+    pub(crate) fn render(f: impl FnOnce()) -> String {
+        let recorder = PrometheusBuilder::new().build_recorder();
+        metrics::with_local_recorder(&recorder, f);
+        recorder.handle().render()
+    }
+
+    /// Rendered sample lines, without `# TYPE` comments and blank lines
+    pub(crate) fn samples(output: &str) -> Vec<&str> {
+        let mut samples: Vec<_> = output
+            .lines()
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+        samples.sort();
+        samples
+    }
+
+    #[test]
+    fn counters_continue_after_a_reset() {
+        let tracker = CounterTracker::default();
+        let reading = |value| vec![("klipper.stats.mcu.bytes_read", vec![], value)];
+
+        let output = render(|| {
+            tracker.observe("mcu", reading(100));
+            tracker.observe("mcu", reading(150));
+            tracker.observe("mcu", reading(150));
+            // Klipper restarted and counts from zero again
+            tracker.observe("mcu", reading(20));
+        });
+        assert_eq!(samples(&output), vec!["klipper_stats_mcu_bytes_read 170"]);
+    }
+
+    #[test]
+    fn counters_of_removed_objects_are_forgotten() {
+        let tracker = CounterTracker::default();
+        let reading = |value| vec![("klipper.stats.mcu.bytes_read", vec![], value)];
+
+        tracker.observe("mcu", reading(100));
+        tracker.observe("mcu rpi", reading(100));
+        tracker.retain(|object| object == "mcu");
+
+        assert_eq!(tracker.last.len(), 1);
+        assert!(tracker.last.contains_key("mcu"));
+    }
+
+    fn extruder() -> klipper::ExtruderStats {
+        serde_json::from_value(json!({
+            "can_extrude": true,
+            "power": 0.5,
+            "pressure_advance": 0.04,
+            "smooth_time": 0.04,
+            "target": 210.0,
+            "temperature": 209.5,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn default_context_exports_without_labels() {
+        let output = render(|| extruder().export(&ExportContext::default()));
+
+        assert_eq!(
+            samples(&output),
+            vec![
+                "klipper_stats_extruder_can_extrude 1",
+                "klipper_stats_extruder_power 0.5",
+                "klipper_stats_extruder_pressure_advance 0.04",
+                "klipper_stats_extruder_smooth_time 0.04",
+                "klipper_stats_extruder_smooth_tmime 0.04",
+                "klipper_stats_extruder_target 210",
+                "klipper_stats_extruder_temp_error 0.5",
+                "klipper_stats_extruder_temperature 209.5",
+            ]
+        );
+    }
+
+    #[test]
+    fn context_name_becomes_name_label() {
+        let ctx = ExportContext {
+            name: Some("extruder1"),
+            ..Default::default()
+        };
+        let output = render(|| extruder().export(&ctx));
+
+        assert!(samples(&output).contains(&"klipper_stats_extruder_power{name=\"extruder1\"} 0.5"));
+    }
+
+    #[test]
+    fn null_as_nan_exports_missing_optional_values() {
+        let ctx = ExportContext {
+            null_as_nan: true,
+            ..Default::default()
+        };
+        let output = render(|| extruder().export(&ctx));
+
+        assert!(samples(&output).contains(&"klipper_stats_extruder_time_offset NaN"));
+    }
 }
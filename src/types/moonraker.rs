@@ -1,16 +1,28 @@
-use crate::types::MetricsExporter;
-use metrics::{counter, describe_counter, gauge, Unit};
+use crate::types::klipper::InfoGauge;
+use crate::types::{CounterReading, ExportContext, MetricsExporter};
+use metrics::{describe_counter, gauge, Unit};
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
 
+/// Host and service statistics from `notify_proc_stat_update`.
+///
+/// Older Moonraker versions report fewer fields, everything besides the
+/// service statistics is optional and only exported when present.
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct MoonrakerStats {
-    cpu_temp: f64,
+    /// `null` on hosts without a CPU temperature sensor
+    #[serde(default)]
+    cpu_temp: Option<f64>,
     moonraker_stats: MoonrakerServiceData,
+    #[serde(default)]
     network: HashMap<String, NetworkInterfaceData>,
-    system_cpu_usage: SystemCpuUsageData,
-    system_memory: SystemMemoryUsageData,
-    websocket_connections: u64,
+    #[serde(default)]
+    system_cpu_usage: Option<SystemCpuUsageData>,
+    #[serde(default)]
+    system_memory: Option<SystemMemoryUsageData>,
+    #[serde(default)]
+    websocket_connections: Option<u64>,
 }
 
 impl MetricsExporter for MoonrakerStats {
@@ -29,20 +41,19 @@ impl MetricsExporter for MoonrakerStats {
         describe_counter!("moonraker.stats.system.memory_used", Unit::Bytes, "");
     }
 
-    fn export(&self, name: Option<&String>) {
-        let mut labels = Vec::new();
-        if let Some(name) = name {
-            labels.push(("name", name.to_owned()));
-        }
+    fn export(&self, ctx: &ExportContext) {
+        let labels = ctx.labels();
 
         // Moonraker Service
         gauge!("moonraker.stats.service.memory", &labels).set(self.moonraker_stats.memory as f64);
         gauge!("moonraker.stats.service.cpu_usage", &labels).set(self.moonraker_stats.cpu_usage);
-        gauge!("moonraker.stats.service.websocket_connections", &labels)
-            .set(self.websocket_connections as f64);
+        if let Some(websocket_connections) = self.websocket_connections {
+            gauge!("moonraker.stats.service.websocket_connections", &labels)
+                .set(websocket_connections as f64);
+        }
 
         // Network interface metrics
-        for (intf, data) in &self.network {
+        for (intf, data) in self.interfaces() {
             let intf_labels: Vec<_> = labels
                 .clone()
                 .into_iter()
@@ -50,66 +61,127 @@ impl MetricsExporter for MoonrakerStats {
                 .collect();
 
             gauge!("moonraker.stats.network.bandwidth", &intf_labels).set(data.bandwidth);
+        }
+
+        // Average CPU usage metric
+        let cpu_labels: Vec<_> = labels
+            .clone()
+            .into_iter()
+            .chain([("cpu", String::from("cpu"))])
+            .collect();
+        if let Some(cpu_temp) = ctx.nullable(self.cpu_temp) {
+            gauge!("moonraker.stats.system.cpu_temp", &cpu_labels).set(cpu_temp);
+        }
 
-            counter!("moonraker.stats.network.rx_bytes", &intf_labels).absolute(data.rx_bytes);
-            counter!("moonraker.stats.network.rx_drop", &intf_labels).absolute(data.rx_drop);
-            counter!("moonraker.stats.network.rx_errs", &intf_labels).absolute(data.rx_errs);
-            counter!("moonraker.stats.network.rx_packets", &intf_labels).absolute(data.rx_packets);
+        if let Some(system_cpu_usage) = &self.system_cpu_usage {
+            gauge!("moonraker.stats.system.cpu_usage", &cpu_labels).set(system_cpu_usage.cpu);
 
-            counter!("moonraker.stats.network.tx_bytes", &intf_labels).absolute(data.tx_bytes);
-            counter!("moonraker.stats.network.tx_drop", &intf_labels).absolute(data.tx_drop);
-            counter!("moonraker.stats.network.tx_errs", &intf_labels).absolute(data.tx_errs);
-            counter!("moonraker.stats.network.tx_packets", &intf_labels).absolute(data.tx_packets);
+            // Per CPU core usage metrics
+            for (core, value) in &system_cpu_usage.cores {
+                let core_labels: Vec<_> = labels
+                    .clone()
+                    .into_iter()
+                    .chain([("cpu", core.to_owned())])
+                    .collect();
+                gauge!("moonraker.stats.system.cpu_usage", &core_labels).set(*value);
+            }
         }
 
-        // Average CPU usage metric
-        {
-            let cpu_labels: Vec<_> = labels
-                .clone()
-                .into_iter()
-                .chain([("cpu", String::from("cpu"))])
-                .collect();
-            gauge!("moonraker.stats.system.cpu_usage", &cpu_labels).set(self.system_cpu_usage.cpu);
-            gauge!("moonraker.stats.system.cpu_temp", &cpu_labels).set(self.cpu_temp);
+        // Memory usage metrics
+        if let Some(system_memory) = &self.system_memory {
+            gauge!("moonraker.stats.system.memory_total", &labels).set(system_memory.total as f64);
+            gauge!("moonraker.stats.system.memory_available", &labels)
+                .set(system_memory.available as f64);
+            gauge!("moonraker.stats.system.memory_used", &labels).set(system_memory.used as f64);
         }
+    }
 
-        // Per CPU core usage metrics
-        for (core, value) in &self.system_cpu_usage.cores {
-            let core_labels: Vec<_> = labels
-                .clone()
+    /// The interface counters reset when the Moonraker host reboots
+    fn counters(&self, ctx: &ExportContext) -> Vec<CounterReading> {
+        let mut readings = Vec::new();
+        for (intf, data) in self.interfaces() {
+            let intf_labels: Vec<_> = ctx
+                .labels()
                 .into_iter()
-                .chain([("cpu", core.to_owned())])
+                .chain([("interface", intf.to_owned())])
                 .collect();
-            gauge!("moonraker.stats.system.cpu_usage", &core_labels).set(*value);
+            let counters = [
+                ("moonraker.stats.network.rx_bytes", Some(data.rx_bytes)),
+                ("moonraker.stats.network.rx_drop", data.rx_drop),
+                ("moonraker.stats.network.rx_errs", data.rx_errs),
+                ("moonraker.stats.network.rx_packets", data.rx_packets),
+                ("moonraker.stats.network.tx_bytes", Some(data.tx_bytes)),
+                ("moonraker.stats.network.tx_drop", data.tx_drop),
+                ("moonraker.stats.network.tx_errs", data.tx_errs),
+                ("moonraker.stats.network.tx_packets", data.tx_packets),
+            ];
+            readings.extend(
+                counters
+                    .into_iter()
+                    .filter_map(|(name, value)| Some((name, intf_labels.clone(), value?))),
+            );
         }
+        readings
+    }
+}
 
-        // Memory usage metrics
-        gauge!("moonraker.stats.system.memory_total", &labels).set(self.system_memory.total as f64);
-        gauge!("moonraker.stats.system.memory_available", &labels)
-            .set(self.system_memory.available as f64);
-        gauge!("moonraker.stats.system.memory_used", &labels).set(self.system_memory.used as f64);
+/// Name of the loopback interface, its traffic says nothing about the printer
+const LOOPBACK_INTERFACE: &str = "lo";
+
+impl MoonrakerStats {
+    /// The network interfaces of the host, without loopback
+    fn interfaces(&self) -> impl Iterator<Item = (&String, &NetworkInterfaceData)> {
+        self.network
+            .iter()
+            .filter(|(intf, _)| intf.as_str() != LOOPBACK_INTERFACE)
+    }
+}
+
+/// Zeroes the bandwidth of network interfaces that disappeared, e.g. an
+/// unplugged USB tether, instead of exporting their last bandwidth forever.
+///
+/// Their counters keep the last value, if an interface comes back with lower
+/// counters the [`crate::types::CounterTracker`] treats that as a reset.
+#[derive(Debug, Default)]
+pub(crate) struct NetworkInterfaceTracker {
+    known: Mutex<HashSet<String>>,
+}
+
+impl NetworkInterfaceTracker {
+    pub fn observe(&self, stats: &MoonrakerStats) {
+        let current: HashSet<_> = stats
+            .interfaces()
+            .map(|(intf, _)| intf.to_owned())
+            .collect();
+        let mut known = self.known.lock().unwrap_or_else(|e| e.into_inner());
+
+        for intf in known.difference(&current) {
+            tracing::info!(interface = intf, "Network interface disappeared");
+            gauge!("moonraker.stats.network.bandwidth", "interface" => intf.to_owned()).set(0.0);
+        }
+        *known = current;
     }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct MoonrakerServiceData {
     cpu_usage: f64,
-    mem_units: String,
     memory: u64,
-    time: f64,
 }
 
+/// Counters of a network interface, drops, errors and packets were added in
+/// later Moonraker versions
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub(crate) struct NetworkInterfaceData {
     bandwidth: f64,
     rx_bytes: u64,
-    rx_drop: u64,
-    rx_errs: u64,
-    rx_packets: u64,
+    rx_drop: Option<u64>,
+    rx_errs: Option<u64>,
+    rx_packets: Option<u64>,
     tx_bytes: u64,
-    tx_drop: u64,
-    tx_errs: u64,
-    tx_packets: u64,
+    tx_drop: Option<u64>,
+    tx_errs: Option<u64>,
+    tx_packets: Option<u64>,
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -125,3 +197,534 @@ pub(crate) struct SystemMemoryUsageData {
     total: u64,
     used: u64,
 }
+
+/// Active spool as reported by Moonraker's Spoolman integration
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct SpoolmanStats {
+    /// `None` when no spool is currently active
+    spool_id: Option<u64>,
+    #[serde(default)]
+    spool: Option<SpoolData>,
+}
+
+impl MetricsExporter for SpoolmanStats {
+    fn export(&self, _ctx: &ExportContext) {
+        // Spoolman ids start at 1, so 0 signals "no active spool"
+        gauge!("moonraker.spoolman.active_spool_id").set(self.spool_id.unwrap_or(0) as f64);
+
+        if let (Some(spool_id), Some(spool)) = (self.spool_id, &self.spool) {
+            if let Some(remaining_weight) = spool.remaining_weight {
+                let labels = vec![("spool_id", spool_id.to_string())];
+                gauge!("moonraker.spoolman.remaining_weight_grams", &labels).set(remaining_weight);
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct SpoolData {
+    remaining_weight: Option<f64>,
+}
+
+/// State of the Moonraker server as reported by `server.info`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ServerInfoStats {
+    klippy_connected: bool,
+    /// Loaded components, e.g. `file_manager` or `spoolman`
+    #[serde(default)]
+    components: Vec<String>,
+    /// Problems found while loading the configuration, e.g. deprecated options
+    #[serde(default)]
+    warnings: Vec<String>,
+}
+
+impl MetricsExporter for ServerInfoStats {
+    fn export(&self, _ctx: &ExportContext) {
+        gauge!("moonraker.server.klipper_connected").set(f64::from(self.klippy_connected));
+        gauge!("moonraker.server.warnings").set(self.warnings.len() as f64);
+        for component in &self.components {
+            let labels = vec![("component", component.to_owned())];
+            gauge!("moonraker.server.component", &labels).set(1.0);
+        }
+    }
+}
+
+/// Identity of the Moonraker host as reported by `machine.system_info`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct SystemInfoStats {
+    #[serde(default)]
+    cpu_info: CpuInfoData,
+    #[serde(default)]
+    distribution: DistributionData,
+    #[serde(default)]
+    python: PythonData,
+    /// Unix time the host booted at, derived from the `system_uptime` of `machine.proc_stats`
+    #[serde(default)]
+    boot_time: Option<f64>,
+}
+
+impl MetricsExporter for SystemInfoStats {
+    fn export(&self, _ctx: &ExportContext) {
+        if let Some(cpu_count) = self.cpu_info.cpu_count {
+            gauge!("moonraker.system.cpu_count").set(cpu_count as f64);
+        }
+        if let Some(boot_time) = self.boot_time {
+            gauge!("moonraker.system.boot_time_seconds").set(boot_time);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct CpuInfoData {
+    cpu_count: Option<u64>,
+    /// Board model, e.g. `Raspberry Pi 4 Model B Rev 1.4`, empty on most PCs
+    #[serde(default)]
+    model: String,
+    #[serde(default)]
+    cpu_desc: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct DistributionData {
+    /// e.g. `Debian GNU/Linux 12 (bookworm)`
+    #[serde(default)]
+    name: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct PythonData {
+    /// e.g. `3.11.2 (main, Mar 13 2023, 12:18:29) [GCC 12.2.0]`
+    #[serde(default)]
+    version_string: String,
+}
+
+/// Exports `moonraker.system.info`, labelled with the distribution and hardware of the host
+#[derive(Debug, Default)]
+pub(crate) struct SystemInfoTracker {
+    info: InfoGauge,
+}
+
+impl SystemInfoTracker {
+    pub fn observe(&self, stats: &SystemInfoStats) {
+        let python_version = stats.python.version_string.split_whitespace().next();
+        self.info.set(
+            "moonraker.system.info",
+            vec![
+                ("distribution", stats.distribution.name.to_owned()),
+                ("model", stats.cpu_info.model.to_owned()),
+                ("cpu_desc", stats.cpu_info.cpu_desc.to_owned()),
+                (
+                    "python_version",
+                    python_version.unwrap_or_default().to_owned(),
+                ),
+            ],
+            1.0,
+        );
+    }
+
+    pub fn export(&self) {
+        self.info.export();
+    }
+}
+
+/// Bits of the Raspberry Pi throttled state (`vcgencmd get_throttled`) and their flag label
+const THROTTLED_FLAGS: [(u32, &str); 8] = [
+    (0, "undervoltage"),
+    (1, "frequency_capped"),
+    (2, "throttled"),
+    (3, "temperature_limited"),
+    (16, "previously_undervoltage"),
+    (17, "previously_frequency_capped"),
+    (18, "previously_throttled"),
+    (19, "previously_temperature_limited"),
+];
+
+/// Throttling of a Raspberry Pi host, from `machine.proc_stats` and `notify_cpu_throttled`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct ThrottledStateStats {
+    bits: u64,
+    /// Human readable flags, e.g. `Under-Voltage Detected`
+    #[serde(default)]
+    flags: Vec<String>,
+}
+
+impl ThrottledStateStats {
+    pub fn flags(&self) -> &[String] {
+        &self.flags
+    }
+}
+
+impl MetricsExporter for ThrottledStateStats {
+    fn export(&self, _ctx: &ExportContext) {
+        for (bit, flag) in THROTTLED_FLAGS {
+            let set = self.bits & (1 << bit) != 0;
+            gauge!("moonraker.system.throttled", "flag" => flag).set(f64::from(set));
+        }
+    }
+}
+
+/// Slicer estimates of the printed file from `server.files.metadata`, all
+/// fields depend on the slicer
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct FileMetadataStats {
+    estimated_time: Option<f64>,
+    /// Filament length in mm
+    filament_total: Option<f64>,
+    /// Filament weight in g
+    filament_weight_total: Option<f64>,
+    object_height: Option<f64>,
+}
+
+impl MetricsExporter for FileMetadataStats {
+    fn export(&self, _ctx: &ExportContext) {
+        let gauges = [
+            ("klipper.print.estimated_time_seconds", self.estimated_time),
+            (
+                "klipper.print.estimated_filament_length_mm",
+                self.filament_total,
+            ),
+            (
+                "klipper.print.estimated_filament_weight_grams",
+                self.filament_weight_total,
+            ),
+            ("klipper.print.object_height_mm", self.object_height),
+        ];
+        for (name, value) in gauges {
+            if let Some(value) = value {
+                gauge!(name).set(value);
+            }
+        }
+    }
+}
+
+/// Software versions known to Moonraker's `update_manager`
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct UpdateStatusStats {
+    #[serde(default)]
+    version_info: HashMap<String, UpdateComponentData>,
+}
+
+impl MetricsExporter for UpdateStatusStats {
+    fn export(&self, _ctx: &ExportContext) {
+        let mut available = 0;
+        for (component, data) in &self.version_info {
+            // Packages of the operating system (`system`) carry no version
+            let (Some(version), Some(remote_version)) = (&data.version, &data.remote_version)
+            else {
+                continue;
+            };
+            // `?` until the remote version has been fetched
+            let outdated = remote_version != "?" && version != remote_version;
+            available += outdated as u64;
+
+            let labels = vec![("component", component.to_owned())];
+            gauge!("moonraker.update.outdated", &labels).set(f64::from(outdated));
+        }
+        gauge!("moonraker.update.available_total").set(available as f64);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct UpdateComponentData {
+    version: Option<String>,
+    remote_version: Option<String>,
+}
+
+/// Devices configured in Moonraker's `[power]` sections
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct PowerDevicesStats {
+    #[serde(default)]
+    devices: Vec<PowerDeviceData>,
+}
+
+impl MetricsExporter for PowerDevicesStats {
+    fn export(&self, _ctx: &ExportContext) {
+        for device in &self.devices {
+            let labels = vec![("device", device.device.to_owned())];
+            // `init` and `error` are reported while the state is unknown
+            gauge!("moonraker.power.device_on", &labels).set(f64::from(device.status == "on"));
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct PowerDeviceData {
+    device: String,
+    status: String,
+}
+
+/// Totals over all jobs recorded by Moonraker's `[history]` component
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct HistoryTotalsStats {
+    job_totals: JobTotalsData,
+}
+
+impl MetricsExporter for HistoryTotalsStats {
+    fn export(&self, _ctx: &ExportContext) {
+        let totals = &self.job_totals;
+        gauge!("moonraker.history.total_time_seconds").set(totals.total_time);
+        gauge!("moonraker.history.total_print_time_seconds").set(totals.total_print_time);
+        gauge!("moonraker.history.total_filament_used_mm").set(totals.total_filament_used);
+        gauge!("moonraker.history.longest_job_seconds").set(totals.longest_job);
+        gauge!("moonraker.history.longest_print_seconds").set(totals.longest_print);
+    }
+
+    /// Deleting jobs from the history lowers the total
+    fn counters(&self, _ctx: &ExportContext) -> Vec<CounterReading> {
+        vec![(
+            "moonraker.history.jobs_total",
+            vec![],
+            self.job_totals.total_jobs,
+        )]
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct JobTotalsData {
+    total_jobs: u64,
+    total_time: f64,
+    total_print_time: f64,
+    total_filament_used: f64,
+    longest_job: f64,
+    #[serde(default)]
+    longest_print: f64,
+}
+
+/// Announcements published by Moonraker, e.g. security notices
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct AnnouncementsStats {
+    #[serde(default)]
+    entries: Vec<AnnouncementEntry>,
+}
+
+impl MetricsExporter for AnnouncementsStats {
+    fn export(&self, _ctx: &ExportContext) {
+        let unread = self.entries.iter().filter(|entry| !entry.dismissed).count();
+        gauge!("moonraker.announcements.unread").set(unread as f64);
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct AnnouncementEntry {
+    #[serde(default)]
+    dismissed: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::tests::{render, samples};
+    use crate::types::CounterTracker;
+    use serde_json::json;
+
+    #[test]
+    fn older_moonraker_proc_stats_are_exported_partially() {
+        // Moonraker v0.7 without system usage and extended network counters,
+        // on a host without CPU temperature sensor
+        let stats: MoonrakerStats = serde_json::from_value(json!({
+            "moonraker_stats": {"time": 1700000000.0, "cpu_usage": 2.5, "memory": 40000, "mem_units": "kB"},
+            "cpu_temp": null,
+            "network": {"wlan0": {"rx_bytes": 1000, "tx_bytes": 2000, "bandwidth": 12.5}},
+        }))
+        .unwrap();
+
+        let ctx = ExportContext::default();
+        let output = render(|| {
+            stats.export(&ctx);
+            CounterTracker::default().observe("moonraker", stats.counters(&ctx));
+        });
+        assert_eq!(
+            samples(&output),
+            vec![
+                "moonraker_stats_network_bandwidth{interface=\"wlan0\"} 12.5",
+                "moonraker_stats_network_rx_bytes{interface=\"wlan0\"} 1000",
+                "moonraker_stats_network_tx_bytes{interface=\"wlan0\"} 2000",
+                "moonraker_stats_service_cpu_usage 2.5",
+                "moonraker_stats_service_memory 40000",
+            ]
+        );
+    }
+
+    #[test]
+    fn vanished_network_interfaces_are_zeroed() {
+        let stats = |interfaces: &[&str]| -> MoonrakerStats {
+            let network: serde_json::Map<_, _> = interfaces
+                .iter()
+                .map(|intf| {
+                    let data = json!({"rx_bytes": 1000, "tx_bytes": 2000, "bandwidth": 12.5});
+                    (intf.to_string(), data)
+                })
+                .collect();
+            serde_json::from_value(json!({
+                "moonraker_stats": {"cpu_usage": 2.5, "memory": 40000},
+                "network": network,
+            }))
+            .unwrap()
+        };
+        let ctx = ExportContext::default();
+        let tracker = NetworkInterfaceTracker::default();
+
+        let output = render(|| {
+            for stats in [stats(&["lo", "wlan0", "usb0"]), stats(&["lo", "wlan0"])] {
+                stats.export(&ctx);
+                tracker.observe(&stats);
+            }
+        });
+        let bandwidth: Vec<_> = samples(&output)
+            .into_iter()
+            .filter(|sample| sample.starts_with("moonraker_stats_network_bandwidth"))
+            .collect();
+        assert_eq!(
+            bandwidth,
+            vec![
+                "moonraker_stats_network_bandwidth{interface=\"usb0\"} 0",
+                "moonraker_stats_network_bandwidth{interface=\"wlan0\"} 12.5",
+            ]
+        );
+    }
+
+    #[test]
+    fn throttled_bits_are_exported_as_flags() {
+        // Under-voltage right now, throttled at some point since boot
+        let state: ThrottledStateStats = serde_json::from_value(json!({
+            "bits": 0x40001,
+            "flags": ["Under-Voltage Detected", "Previously Throttled"]
+        }))
+        .unwrap();
+        let output = render(|| state.export(&ExportContext::default()));
+
+        assert_eq!(
+            samples(&output),
+            vec![
+                "moonraker_system_throttled{flag=\"frequency_capped\"} 0",
+                "moonraker_system_throttled{flag=\"previously_frequency_capped\"} 0",
+                "moonraker_system_throttled{flag=\"previously_temperature_limited\"} 0",
+                "moonraker_system_throttled{flag=\"previously_throttled\"} 1",
+                "moonraker_system_throttled{flag=\"previously_undervoltage\"} 0",
+                "moonraker_system_throttled{flag=\"temperature_limited\"} 0",
+                "moonraker_system_throttled{flag=\"throttled\"} 0",
+                "moonraker_system_throttled{flag=\"undervoltage\"} 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn slicer_estimates_are_exported() {
+        let metadata: FileMetadataStats = serde_json::from_value(json!({
+            "filename": "benchy.gcode",
+            "size": 1290612,
+            "slicer": "PrusaSlicer",
+            "estimated_time": 2814,
+            "filament_total": 4241.36,
+            "filament_weight_total": 12.65,
+            "object_height": 48.0
+        }))
+        .unwrap();
+        let output = render(|| metadata.export(&ExportContext::default()));
+
+        assert_eq!(
+            samples(&output),
+            vec![
+                "klipper_print_estimated_filament_length_mm 4241.36",
+                "klipper_print_estimated_filament_weight_grams 12.65",
+                "klipper_print_estimated_time_seconds 2814",
+                "klipper_print_object_height_mm 48",
+            ]
+        );
+    }
+
+    #[test]
+    fn system_info_identifies_the_host() {
+        let info: SystemInfoStats = serde_json::from_value(json!({
+            "cpu_info": {
+                "cpu_count": 4,
+                "bits": "64bit",
+                "processor": "aarch64",
+                "cpu_desc": "",
+                "serial_number": "b898bdb4",
+                "hardware_desc": "",
+                "model": "Raspberry Pi 4 Model B Rev 1.4",
+                "total_memory": 3884836,
+                "memory_units": "kB"
+            },
+            "distribution": {
+                "name": "Debian GNU/Linux 12 (bookworm)",
+                "id": "debian",
+                "version": "12"
+            },
+            "python": {
+                "version": [3, 11, 2, "final", 0],
+                "version_string": "3.11.2 (main, Mar 13 2023, 12:18:29) [GCC 12.2.0]"
+            },
+            "boot_time": 1700000000.0
+        }))
+        .unwrap();
+        let tracker = SystemInfoTracker::default();
+        let output = render(|| {
+            info.export(&ExportContext::default());
+            tracker.observe(&info);
+        });
+
+        assert_eq!(
+            samples(&output),
+            vec![
+                "moonraker_system_boot_time_seconds 1700000000",
+                "moonraker_system_cpu_count 4",
+                "moonraker_system_info{distribution=\"Debian GNU/Linux 12 (bookworm)\",model=\"Raspberry Pi 4 Model B Rev 1.4\",cpu_desc=\"\",python_version=\"3.11.2\"} 1",
+            ]
+        );
+    }
+
+    #[test]
+    fn server_warnings_are_counted() {
+        let info: ServerInfoStats = serde_json::from_value(json!({
+            "klippy_connected": true,
+            "klippy_state": "ready",
+            "components": ["file_manager", "spoolman"],
+            "failed_components": [],
+            "warnings": [
+                "Unparsed config section [foo] detected",
+                "Option 'enable_object_processing' is deprecated"
+            ],
+            "websocket_count": 2,
+            "moonraker_version": "v0.9.3-0-g1e0d9c4"
+        }))
+        .unwrap();
+
+        let output = render(|| info.export(&ExportContext::default()));
+        assert_eq!(
+            samples(&output),
+            vec![
+                "moonraker_server_component{component=\"file_manager\"} 1",
+                "moonraker_server_component{component=\"spoolman\"} 1",
+                "moonraker_server_klipper_connected 1",
+                "moonraker_server_warnings 2",
+            ]
+        );
+    }
+
+    #[test]
+    fn outdated_components_are_flagged() {
+        let status: UpdateStatusStats = serde_json::from_value(json!({
+            "busy": false,
+            "version_info": {
+                "system": {"package_count": 4, "package_list": ["libtiff5"]},
+                "moonraker": {"version": "v0.9.3-10", "remote_version": "v0.9.3-12"},
+                "klipper": {"version": "v0.12.0-114", "remote_version": "v0.12.0-114"},
+                "mainsail": {"name": "mainsail", "version": "v2.11.2", "remote_version": "?"}
+            }
+        }))
+        .unwrap();
+
+        let output = render(|| status.export(&ExportContext::default()));
+        assert_eq!(
+            samples(&output),
+            vec![
+                "moonraker_update_available_total 1",
+                "moonraker_update_outdated{component=\"klipper\"} 0",
+                "moonraker_update_outdated{component=\"mainsail\"} 0",
+                "moonraker_update_outdated{component=\"moonraker\"} 1",
+            ]
+        );
+    }
+}